@@ -1,10 +1,6 @@
-mod compressor;
-mod table;
-
-use crate::compressor::Compressor;
-use crate::table::PRESET_SYMBOLS;
 use bpaf::{Bpaf, Parser};
 use bstr::ByteSlice;
+use jetfsst::{Compressor, PRESET_SYMBOLS};
 use url::Url;
 use wsclient::Frame;
 
@@ -1,3 +1,4 @@
+use crate::ESCAPE;
 use fsst::{CompressorBuilder, Symbol};
 use std::fmt;
 
@@ -94,8 +95,13 @@ impl Compressor {
 
     pub fn compress(&self, xs: &[u8]) -> Vec<u8> {
         let mut compressed = self.inner.compress(xs);
+        let mut escaped = false;
         for x in &mut compressed {
-            *x = self.map_to[*x as usize];
+            // Escaped bytes are literals, not codes, so they stay as they are
+            if !escaped {
+                *x = self.map_to[*x as usize];
+            }
+            escaped = !escaped && *x == ESCAPE;
         }
         compressed
     }
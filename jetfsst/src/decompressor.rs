@@ -0,0 +1,45 @@
+use crate::ESCAPE;
+use std::fmt;
+
+/// The inverse of [`crate::Compressor`].  Doesn't depend on fsst at all: this
+/// is all a client needs.
+pub struct Decompressor {
+    symbols: Vec<Vec<u8>>,
+}
+
+impl Decompressor {
+    /// `symbols` must be the same table the data was compressed with
+    pub fn new(symbols: &[&str]) -> Self {
+        assert!(symbols.len() <= ESCAPE as usize);
+        Decompressor {
+            symbols: symbols.iter().map(|x| x.as_bytes().to_vec()).collect(),
+        }
+    }
+
+    pub fn decompress(&self, xs: &[u8]) -> Result<Vec<u8>, BadCode> {
+        let mut out = Vec::with_capacity(xs.len() * 3);
+        let mut iter = xs.iter();
+        while let Some(&code) = iter.next() {
+            if code == ESCAPE {
+                out.push(*iter.next().ok_or(BadCode(code))?);
+            } else {
+                let sym = self.symbols.get(code as usize).ok_or(BadCode(code))?;
+                out.extend_from_slice(sym);
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// The compressed data contained a code which isn't in the symbol table (or
+/// ended with an escape)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadCode(pub u8);
+
+impl fmt::Display for BadCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Bad FSST code: {:#04x}", self.0)
+    }
+}
+
+impl std::error::Error for BadCode {}
@@ -0,0 +1,44 @@
+//! FSST compression with a preset symbol table
+//!
+//! Normally FSST trains a new symbol table for each batch of data, and ships
+//! the table alongside it.  Instead, we use a single table, [`PRESET_SYMBOLS`],
+//! tuned for jetstream events.  Since both sides already know the table, every
+//! message can be compressed independently.
+//!
+//! The format is simple: each byte of compressed data is a code.  Code `0xff`
+//! means "the next byte is a literal"; any other code `i` stands for
+//! `PRESET_SYMBOLS[i]`.
+
+mod compressor;
+mod decompressor;
+mod table;
+
+pub use crate::compressor::Compressor;
+pub use crate::decompressor::{BadCode, Decompressor};
+pub use crate::table::PRESET_SYMBOLS;
+
+/// The code which introduces a literal byte
+pub const ESCAPE: u8 = 0xff;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress() {
+        let dcmprsr = Decompressor::new(&PRESET_SYMBOLS);
+        let x = dcmprsr.decompress(&[0x02, 0x0a, 0xff, 0x07, 0xff, 0xff]);
+        assert_eq!(x.unwrap(), b"02\n\x07\xff");
+        assert_eq!(dcmprsr.decompress(&[0x41, 0xff]), Err(BadCode(0xff)));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let cmprsr = Compressor::new(&PRESET_SYMBOLS);
+        let dcmprsr = Decompressor::new(&PRESET_SYMBOLS);
+        let x = "{\"did\":\"did:plc:abc\",\"time_us\":1743000000000000,\"text\":\"héllo\x07\"}";
+        let compressed = cmprsr.compress(x.as_bytes());
+        assert!(compressed.len() < x.len());
+        assert_eq!(dcmprsr.decompress(&compressed).unwrap(), x.as_bytes());
+    }
+}
@@ -0,0 +1,246 @@
+//! The admin API
+//!
+//! Operators can inspect and control a running relay through a Unix socket at
+//! `$RUNTIME_DIRECTORY/admin.sock`.  Only the user jetrelay runs as can
+//! connect to it.  The protocol is line-based: send a command, and get back
+//! some lines of text.  For example:
+//!
+//! ```console
+//! $ echo clients | socat - UNIX-CONNECT:/run/jetrelay/admin.sock
+//! ```
+//!
+//! Send `help` for the list of commands.  Commands which concern the clients
+//! are passed to the runloop (which owns them), and the rest are handled
+//! directly by the admin threads.  Each connection gets a thread of its own,
+//! so a session which has been left open doesn't hold up anyone else.
+
+use crate::upstream::{INGEST_PAUSED, SWEEP_REQUESTED};
+use crate::{Client, ClientId};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+use tracing::*;
+use tracing_subscriber::{EnvFilter, Registry, reload};
+
+/// Lets us change the log filter at runtime
+pub static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Admin connections which go quiet for this long are dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// The runloop should get round to our requests well within this time
+const RUNLOOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELP: &str = "\
+clients                       List the connected clients
+kick <id> [<code> [<reason>]] Send a client a close frame (default code: 1000)
+pause                         Stop reading from upstream (and disconnect)
+resume                        Start reading from upstream again
+sweep                         Drop old data, as if the age limit had been hit
+log <filter>                  Change the log filter (same syntax as RUST_LOG)
+";
+
+/// A request which has to be handled by the runloop
+pub struct Command {
+    request: Request,
+    reply: Sender<String>,
+}
+
+enum Request {
+    ListClients,
+    Kick {
+        client_id: ClientId,
+        code: u16,
+        reason: String,
+    },
+}
+
+/// Starts the admin thread.  The runloop should pass whatever comes out of
+/// the channel to [`handle_commands`].
+pub fn listen(dir: &Path) -> Result<Receiver<Command>> {
+    let path = dir.join("admin.sock");
+    // The socket may be left over from a previous run
+    if std::fs::symlink_metadata(&path).is_ok_and(|x| x.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path).with_context(|| path.display().to_string())?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Admin API listening at {}", path.display());
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("admin".to_owned())
+        .spawn(move || serve(listener, tx))?;
+    Ok(rx)
+}
+
+/// Runs forever
+fn serve(listener: UnixListener, tx: Sender<Command>) {
+    let _g = info_span!("admin thread").entered();
+    for conn in listener.incoming() {
+        let conn = match conn {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Couldn't accept an admin connection: {e}");
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let span = Span::current();
+        let spawned = std::thread::Builder::new()
+            .name("admin_conn".to_owned())
+            .spawn(move || {
+                let _g = span.entered();
+                if let Err(e) = handle_conn(conn, &tx) {
+                    debug!("Admin connection failed: {e:#}");
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("Couldn't start a thread for an admin connection: {e}");
+        }
+    }
+}
+
+fn handle_conn(conn: UnixStream, tx: &Sender<Command>) -> Result<()> {
+    conn.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut out = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        info!("Admin command: {line}");
+        let response = run_command(line, tx).unwrap_or_else(|e| format!("error: {e:#}\n"));
+        out.write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn run_command(line: &str, tx: &Sender<Command>) -> Result<String> {
+    let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+    let args = args.trim();
+    match cmd {
+        "help" => Ok(HELP.to_owned()),
+        "clients" => ask_runloop(tx, Request::ListClients),
+        "kick" => {
+            let mut args = args.splitn(3, ' ');
+            let client_id = args.next().context("Expected a client ID")?.parse()?;
+            let code = match args.next() {
+                Some(x) => x.parse()?,
+                None => 1000,
+            };
+            // 1004-1006 and 1015 mustn't be sent over the wire
+            ensure!(
+                matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999),
+                "Bad close code: {code}"
+            );
+            let reason = args.next().unwrap_or("").to_owned();
+            // A control frame's payload is limited to 125 bytes
+            ensure!(reason.len() <= 123, "The reason is too long");
+            let request = Request::Kick {
+                client_id,
+                code,
+                reason,
+            };
+            ask_runloop(tx, request)
+        }
+        "pause" => {
+            INGEST_PAUSED.store(true, Ordering::Relaxed);
+            Ok("Ingest paused\n".to_owned())
+        }
+        "resume" => {
+            INGEST_PAUSED.store(false, Ordering::Relaxed);
+            Ok("Ingest resumed\n".to_owned())
+        }
+        "sweep" => {
+            SWEEP_REQUESTED.store(true, Ordering::Relaxed);
+            Ok("Old data will be dropped shortly\n".to_owned())
+        }
+        "log" => {
+            let filter = EnvFilter::try_new(args)?;
+            let handle = LOG_FILTER.get().context("Logging isn't set up")?;
+            handle.reload(filter)?;
+            info!("Log filter changed to {args:?}");
+            Ok(format!("Log filter changed to {args:?}\n"))
+        }
+        x => bail!("Unknown command: {x:?} (try \"help\")"),
+    }
+}
+
+fn ask_runloop(tx: &Sender<Command>, request: Request) -> Result<String> {
+    let (reply, rx) = std::sync::mpsc::channel();
+    tx.send(Command { request, reply })
+        .map_err(|_| anyhow!("The runloop has gone away"))?;
+    crate::io::wake_runloop();
+    Ok(rx.recv_timeout(RUNLOOP_TIMEOUT)?)
+}
+
+/// Called by the runloop
+pub fn handle_commands(rx: &Receiver<Command>, clients: &mut HashMap<ClientId, Client>) {
+    while let Ok(cmd) = rx.try_recv() {
+        let response = match cmd.request {
+            Request::ListClients => list_clients(clients),
+            Request::Kick {
+                client_id,
+                code,
+                reason,
+            } => match clients.get_mut(&client_id) {
+                Some(client) if client.closing => {
+                    format!("Client {client_id} is already closing\n")
+                }
+                Some(client) => {
+                    let _g = info_span!("", client_id).entered();
+                    warn!("Disconnecting client at an admin's request");
+                    crate::control::close(client, code, &reason);
+                    format!("Disconnecting client {client_id}\n")
+                }
+                None => format!("error: No such client: {client_id}\n"),
+            },
+        };
+        // The admin may have given up waiting
+        let _ = cmd.reply.send(response);
+    }
+}
+
+fn list_clients(clients: &HashMap<ClientId, Client>) -> String {
+    let mut ids: Vec<_> = clients.keys().copied().collect();
+    ids.sort();
+    let mut out = String::new();
+    for id in ids {
+        let client = &clients[&id];
+        let peer_addr = match client.peer_addr {
+            Some(x) => x.to_string(),
+            None => "?".to_owned(),
+        };
+        let flags = [
+            ("closing", client.closing),
+            ("skip_to_live", client.skip_to_live),
+            ("ping_pending", client.ping_pending),
+            ("copy_in_flight", client.copy_in_flight),
+            ("send_in_flight", client.send_in_flight),
+        ];
+        let flags: Vec<&str> = flags.iter().filter(|x| x.1).map(|x| x.0).collect();
+        writeln!(
+            out,
+            "{id} peer={peer_addr} offset={} lag={:?} sent={} feed={:?} compression={:?} \
+             max_message_size={} slow_consumer={:?} flags={}",
+            client.offset,
+            client.lag,
+            client.bytes_sent,
+            client.feed.to_string(),
+            client.compression,
+            client.max_message_size,
+            client.slow_consumer,
+            flags.join(","),
+        )
+        .unwrap();
+    }
+    out
+}
@@ -0,0 +1,370 @@
+//! Filtering by collection
+//!
+//! Alongside `jetrelay.dat`, the upstream copier writes each frame to a file
+//! specific to its collection (eg. `jetrelay.app.bsky.feed.post.dat`).  Events
+//! which don't belong to a collection (identity and account events) go into
+//! `jetrelay.other.dat`: jetstream sends those to all clients, regardless of
+//! what collections they asked for.  Each file has its own timestamp index.
+//!
+//! Clients which specify `wantedCollections` are fed from the relevant files,
+//! interleaved in timestamp order.  We only switch from one file to another at
+//! frame boundaries.
+//!
+//! Upstream decides which collections exist, so the number of files (and fds)
+//! is capped at `MAX_COLLECTIONS`.  Once we hit the cap, events from any new
+//! collections go into `jetrelay.other.dat`.  Filtered clients still get the
+//! events they asked for, but they also get these ones.
+
+use crate::datafile::{DataFile, WATERMARK};
+use crate::upstream::Timestamp;
+use anyhow::{Result, ensure};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::*;
+
+/// The pseudo-collection for events which aren't commits
+const OTHER: &str = "other";
+
+/// Same limit as the official jetstream server
+const MAX_WANTED_COLLECTIONS: usize = 100;
+
+/// Including `OTHER`
+const MAX_COLLECTIONS: usize = 1000;
+
+/// All the collection files which exist, in order of creation
+pub static COLLECTIONS: Mutex<Vec<Arc<DataFile>>> = Mutex::new(Vec::new());
+/// The length of `COLLECTIONS`.  Lets the runloop check for new collections
+/// without taking the lock.
+pub static N_COLLECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The upstream copier's handle on the collection files
+pub struct Writer {
+    dir: PathBuf,
+    files: HashMap<String, Arc<DataFile>>,
+    /// We've hit `MAX_COLLECTIONS`
+    full: bool,
+}
+
+impl Writer {
+    pub fn new(dir: PathBuf) -> Result<Writer> {
+        let mut writer = Writer {
+            dir,
+            files: HashMap::new(),
+            full: false,
+        };
+        // Create this one up-front, so filtered clients always have at least
+        // one file to follow
+        writer.get_or_create(OTHER)?;
+        Ok(writer)
+    }
+
+    fn get_or_create(&mut self, name: &str) -> Result<&Arc<DataFile>> {
+        if !self.files.contains_key(name) {
+            if self.files.len() >= MAX_COLLECTIONS {
+                if !self.full {
+                    warn!("Too many collections; putting new ones in {OTHER}");
+                    self.full = true;
+                }
+                return Ok(&self.files[OTHER]);
+            }
+            let file = Arc::new(DataFile::create(&self.dir, name)?);
+            info!("New collection: {name}");
+            let mut all = COLLECTIONS.lock().unwrap();
+            all.push(file.clone());
+            N_COLLECTIONS.store(all.len(), Ordering::Release);
+            self.files.insert(name.to_owned(), file);
+        }
+        Ok(&self.files[name])
+    }
+
+    /// `collection` is `None` for events which aren't commits.  Returns the
+    /// file which the frame was written to.
+    pub fn write(
+        &mut self,
+        collection: Option<&str>,
+        timestamp: Timestamp,
+        bytes: &[u8],
+    ) -> Result<Arc<DataFile>> {
+        let name = collection.unwrap_or(OTHER);
+        // The name ends up in a path, so we'd better make sure it's valid
+        ensure!(
+            is_valid_nsid(name) || name == OTHER,
+            "Bad collection: {name}"
+        );
+        let file = self.get_or_create(name)?;
+        file.append(timestamp, bytes)?;
+        Ok(file.clone())
+    }
+
+    pub fn drop_old_data(&self, ts: Timestamp) {
+        for file in self.files.values() {
+            file.drop_old_data(ts);
+        }
+    }
+
+    pub fn punch_holes(&self) -> Result<()> {
+        for file in self.files.values() {
+            file.punch_hole()?;
+        }
+        Ok(())
+    }
+}
+
+/// The collections a client asked for.  Follows the official jetstream server:
+/// entries ending in `.*` match any collection with that prefix; anything else
+/// must be a complete NSID.
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFilter {
+    exact: Vec<String>,
+    prefixes: Vec<String>,
+}
+
+impl CollectionFilter {
+    pub fn new(wanted: &[String]) -> Result<CollectionFilter> {
+        ensure!(
+            wanted.len() <= MAX_WANTED_COLLECTIONS,
+            "Too many wanted collections: {}",
+            wanted.len()
+        );
+        let mut filter = CollectionFilter::default();
+        for x in wanted {
+            if let Some(prefix) = x.strip_suffix('*') {
+                let valid = prefix
+                    .strip_suffix('.')
+                    .is_some_and(|x| x.split('.').all(is_valid_segment));
+                ensure!(valid, "Bad collection prefix: {x}");
+                filter.prefixes.push(prefix.to_owned());
+            } else {
+                ensure!(is_valid_nsid(x), "Bad collection: {x}");
+                filter.exact.push(x.clone());
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, collection: &str) -> bool {
+        collection == OTHER
+            || self.exact.iter().any(|x| x == collection)
+            || self
+                .prefixes
+                .iter()
+                .any(|x| collection.starts_with(x.as_str()))
+    }
+}
+
+impl fmt::Display for CollectionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let exact = self.exact.iter().map(|x| x.to_owned());
+        let prefixes = self.prefixes.iter().map(|x| format!("{x}*"));
+        let all: Vec<String> = exact.chain(prefixes).collect();
+        f.write_str(&all.join(","))
+    }
+}
+
+fn is_valid_segment(x: &str) -> bool {
+    !x.is_empty()
+        && x.len() <= 63
+        && x.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-')
+        && !x.starts_with('-')
+        && !x.ends_with('-')
+}
+
+/// A simplified version of the NSID syntax rules.  Rejects anything which
+/// would be dangerous to put in a file name.
+fn is_valid_nsid(x: &str) -> bool {
+    x.len() <= 317 && x.split('.').count() >= 3 && x.split('.').all(is_valid_segment)
+}
+
+/// One of the files a client is following
+#[derive(Debug)]
+struct Source {
+    file: Arc<DataFile>,
+    /// All frames in `file` up to and including this timestamp have been sent
+    /// (or skipped)
+    after: Timestamp,
+}
+
+/// A contiguous range of frames, all from the same file
+#[derive(Debug)]
+pub struct Run {
+    pub file: Arc<DataFile>,
+    pub offset: u64,
+    pub end: u64,
+    /// The end of the last frame we put (some of) in the pipe.  If this is
+    /// past `offset`, we're in the middle of a frame.
+    pub frame_end: u64,
+}
+
+/// Tracks a client's position in each of the files it's following
+#[derive(Debug)]
+pub struct Interleaver {
+    /// If `None`, the set of files is fixed
+    filter: Option<CollectionFilter>,
+    sources: Vec<Source>,
+    /// How many entries of `COLLECTIONS` we've already looked at
+    n_known: usize,
+    /// The run we're currently sending.  We mustn't start sending from a
+    /// different file until this is finished.
+    pub run: Option<Run>,
+}
+
+impl Interleaver {
+    /// Start following all collections which match `filter`, from the first
+    /// frame after `after`
+    pub fn new(filter: CollectionFilter, after: Timestamp) -> Interleaver {
+        let mut this = Interleaver {
+            filter: Some(filter),
+            sources: vec![],
+            n_known: 0,
+            run: None,
+        };
+        this.add_new_collections(after);
+        this
+    }
+
+    /// Follow a single file, from the first frame after `after`
+    pub fn fixed(file: Arc<DataFile>, after: Timestamp) -> Interleaver {
+        Interleaver {
+            filter: None,
+            sources: vec![Source { file, after }],
+            n_known: 0,
+            run: None,
+        }
+    }
+
+    pub fn filter(&self) -> Option<&CollectionFilter> {
+        self.filter.as_ref()
+    }
+
+    /// Pick up collection files which have been created since we last checked
+    fn add_new_collections(&mut self, after: Timestamp) {
+        let Some(filter) = &self.filter else {
+            return;
+        };
+        let n = N_COLLECTIONS.load(Ordering::Acquire);
+        if n == self.n_known {
+            return;
+        }
+        let all = COLLECTIONS.lock().unwrap();
+        for file in &all[self.n_known..] {
+            if filter.matches(&file.name) {
+                debug!("Following {}", file.name);
+                let file = file.clone();
+                self.sources.push(Source { file, after });
+            }
+        }
+        self.n_known = all.len();
+    }
+
+    /// Work out which frames to send next.  We pick the file with the oldest
+    /// unsent frame, and take as many frames from it as we can before some
+    /// other file has something older.
+    pub fn next_run(&mut self) -> Option<&mut Run> {
+        let watermark = Timestamp(WATERMARK.load(Ordering::Acquire));
+        // Newly-discovered files only contain new data, so there's no point
+        // looking further back than the furthest we've got in any file
+        let furthest = self.sources.iter().map(|x| x.after).max();
+        self.add_new_collections(furthest.unwrap_or(watermark));
+
+        let mut best: Option<(usize, Timestamp, u64)> = None;
+        let mut bound = watermark;
+        for (i, src) in self.sources.iter().enumerate() {
+            let Some((ts, offset)) = src.file.next_frame(src.after) else {
+                continue;
+            };
+            if ts > watermark {
+                continue; // Not safe to send yet
+            }
+            match best {
+                Some((_, best_ts, _)) if best_ts <= ts => bound = bound.min(ts),
+                Some((_, best_ts, _)) => {
+                    bound = bound.min(best_ts);
+                    best = Some((i, ts, offset));
+                }
+                None => best = Some((i, ts, offset)),
+            }
+        }
+        let (i, _, offset) = best?;
+        let src = &mut self.sources[i];
+        // The length must be read before the index, so that any frames it
+        // covers are guaranteed to be in the index
+        let len = src.file.len.load(Ordering::Acquire);
+        let end = src.file.next_frame(bound).map_or(len, |(_, x)| x);
+        src.after = bound;
+        self.run = Some(Run {
+            file: src.file.clone(),
+            offset,
+            end,
+            frame_end: offset,
+        });
+        self.run.as_mut()
+    }
+
+    /// Whether any of the files has dropped frames which we haven't sent yet
+    pub fn fell_behind(&self) -> bool {
+        self.sources
+            .iter()
+            .any(|src| src.after.0 < src.file.dropped_up_to.load(Ordering::Acquire))
+    }
+
+    /// Skip over any frames which have been dropped.  Returns our new
+    /// position.
+    pub fn skip_dropped_data(&mut self) -> Timestamp {
+        for src in &mut self.sources {
+            let dropped_up_to = Timestamp(src.file.dropped_up_to.load(Ordering::Acquire));
+            src.after = src.after.max(dropped_up_to);
+        }
+        self.position()
+    }
+
+    /// Skip all frames up to and including `ts`.  There mustn't be a run in
+    /// progress.
+    pub fn skip_to(&mut self, ts: Timestamp) {
+        for src in &mut self.sources {
+            src.after = src.after.max(ts);
+        }
+    }
+
+    /// Everything up to and including this timestamp has been sent, assuming
+    /// there's no run in progress
+    pub fn position(&mut self) -> Timestamp {
+        let watermark = Timestamp(WATERMARK.load(Ordering::Acquire));
+        let furthest = self.sources.iter().map(|x| x.after).max();
+        self.add_new_collections(furthest.unwrap_or(watermark));
+        // Frames up to the watermark are all in the index, so if a file has
+        // nothing after `after`, then we've sent everything up to the watermark
+        self.sources
+            .iter()
+            .filter_map(|src| src.file.next_frame(src.after))
+            .map(|(ts, _)| ts - Duration::from_micros(1))
+            .fold(watermark, Timestamp::min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(xs: &[&str]) -> Result<CollectionFilter> {
+        CollectionFilter::new(&xs.iter().map(|x| x.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn wildcards() {
+        let f = filter(&["app.bsky.feed.post", "app.bsky.graph.*"]).unwrap();
+        assert!(f.matches("app.bsky.feed.post"));
+        assert!(!f.matches("app.bsky.feed.like"));
+        assert!(f.matches("app.bsky.graph.follow"));
+        assert!(!f.matches("app.bsky.graphs.follow"));
+        assert!(f.matches(OTHER));
+
+        assert!(filter(&["app.bsky.graph.fo*"]).is_err());
+        assert!(filter(&["*"]).is_err());
+        assert!(filter(&["../../etc/passwd"]).is_err());
+        assert!(filter(&["app.bsky"]).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! The compressed versions of the feed
+//!
+//! We compress each event once, when it arrives, and write it to a file
+//! alongside `jetrelay.dat`.  Compressed clients are then fed from that file,
+//! just like everyone else is fed from `jetrelay.dat`.  Either way, clients get
+//! binary frames, each containing a single compressed event.
+//!
+//! Clients which ask for `compress=true` get zstd, fed from `jetrelay.zstd.dat`.
+//! The events are compressed using the same dictionary as the official
+//! jetstream server, so existing clients can decompress them.
+//!
+//! Clients which ask for `fsst=true` get FSST, fed from `jetrelay.fsst.dat`.
+//! This uses the preset symbol table from the `jetfsst` crate, so there's no
+//! dictionary to distribute, and a decoder is only a few lines long.
+//!
+//! Each of these costs CPU for every event, so they're off unless enabled
+//! (with `ZSTD_DICTIONARY` and `ENABLE_FSST` respectively).  Compression can't
+//! be combined with filtering: clients which ask for both are turned away.
+
+use crate::datafile::DataFile;
+use crate::handshake::{Compression, Options};
+use crate::upstream::Timestamp;
+use anyhow::{Result, anyhow, bail};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use wsclient::{Frame, OpCode};
+
+/// Only set if zstd compression is enabled
+pub static ZSTD_FILE: OnceLock<Arc<DataFile>> = OnceLock::new();
+/// Only set if FSST is enabled
+pub static FSST_FILE: OnceLock<Arc<DataFile>> = OnceLock::new();
+
+pub struct ZstdWriter {
+    compressor: zstd::bulk::Compressor<'static>,
+    file: Arc<DataFile>,
+}
+
+impl ZstdWriter {
+    pub fn new(dir: &Path, dictionary: &[u8]) -> Result<ZstdWriter> {
+        let level = zstd::DEFAULT_COMPRESSION_LEVEL;
+        let compressor = zstd::bulk::Compressor::with_dictionary(level, dictionary)?;
+        let file = Arc::new(DataFile::create(dir, "zstd")?);
+        ZSTD_FILE
+            .set(file.clone())
+            .map_err(|_| anyhow!("Compression was initialised twice"))?;
+        Ok(ZstdWriter { compressor, file })
+    }
+
+    pub fn write(&mut self, timestamp: Timestamp, frame: &Frame<impl AsRef<[u8]>>) -> Result<()> {
+        let compressed = self.compressor.compress(frame.payload())?;
+        let frame = Frame::new(OpCode::Binary, &compressed);
+        self.file.append(timestamp, &frame.bytes)
+    }
+
+    pub fn drop_old_data(&self, ts: Timestamp) {
+        self.file.drop_old_data(ts)
+    }
+
+    pub fn punch_hole(&self) -> Result<()> {
+        self.file.punch_hole()
+    }
+}
+
+pub struct FsstWriter {
+    compressor: jetfsst::Compressor,
+    file: Arc<DataFile>,
+}
+
+impl FsstWriter {
+    pub fn new(dir: &Path) -> Result<FsstWriter> {
+        let compressor = jetfsst::Compressor::new(&jetfsst::PRESET_SYMBOLS);
+        let file = Arc::new(DataFile::create(dir, "fsst")?);
+        FSST_FILE
+            .set(file.clone())
+            .map_err(|_| anyhow!("FSST was initialised twice"))?;
+        Ok(FsstWriter { compressor, file })
+    }
+
+    pub fn write(&self, timestamp: Timestamp, frame: &Frame<impl AsRef<[u8]>>) -> Result<()> {
+        let compressed = self.compressor.compress(frame.payload());
+        let frame = Frame::new(OpCode::Binary, &compressed);
+        self.file.append(timestamp, &frame.bytes)
+    }
+
+    pub fn drop_old_data(&self, ts: Timestamp) {
+        self.file.drop_old_data(ts)
+    }
+
+    pub fn punch_hole(&self) -> Result<()> {
+        self.file.punch_hole()
+    }
+}
+
+/// Check that we can give the client what it asked for
+pub fn check(compression: Compression, options: &Options) -> Result<()> {
+    let (param, enabled) = match compression {
+        Compression::None => return Ok(()),
+        Compression::Zstd => ("compress", ZSTD_FILE.get().is_some()),
+        Compression::Fsst => ("fsst", FSST_FILE.get().is_some()),
+    };
+    if !enabled {
+        bail!("{param}=true isn't enabled on this server");
+    }
+    if !options.wanted_collections.is_empty() || !options.wanted_dids.is_empty() {
+        bail!("{param}=true isn't supported with filtering");
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! Messages from clients
+//!
+//! Like the official jetstream server, clients may send us JSON messages in
+//! text frames.  The only one we understand is `options_update`, which replaces
+//! the client's `wantedCollections`, `wantedDids`, and `maxMessageSizeBytes`.
+//! Clients which connect with `requireHello` aren't sent anything until their
+//! first `options_update` arrives.
+//!
+//! The runloop polls each client's socket, and when it becomes readable we read
+//! whatever's there (without blocking).  New options aren't applied
+//! immediately: see [`crate::Client::apply_pending_options`].
+//!
+//! We also handle the websocket control frames here.  Pings are answered with
+//! pongs, and a close frame gets a close frame in reply, after which we shut
+//! the socket down.  Our own control frames can't be sent while a data frame
+//! is half-way out of the pipe, so they wait in the client's outbox until the
+//! pipe has drained at a frame boundary.  A client which has stopped reading
+//! never gets there, so when we close a connection we only wait
+//! `CLOSE_TIMEOUT` for the close frame to go out before shutting it down.
+//!
+//! Clients we haven't heard from for half the keepalive timeout get pinged.
+//! If we still haven't heard anything by the end of the timeout, we give up on
+//! them.
+
+use crate::Client;
+use crate::handshake::Options;
+use anyhow::{Result, bail, ensure};
+use rustix::io::Errno;
+use rustix::net::{RecvFlags, SendFlags, Shutdown};
+use std::time::{Duration, Instant};
+use tracing::*;
+use wsclient::{Frame, OpCode};
+
+/// Clients have no business sending us anything bigger than this.  It's enough
+/// for an `options_update` with the maximum number of DIDs.
+const MAX_MESSAGE_SIZE: usize = 1 << 20; // 1 MiB
+
+/// How long we wait for our close frame to go out before giving up on it
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Read everything the client has sent us.  Returns `false` if the client has
+/// gone away.
+pub fn read_messages(client: &mut Client) -> Result<bool> {
+    let mut chunk = [0; 4096];
+    loop {
+        match rustix::net::recv(&client.conn, &mut chunk, RecvFlags::DONTWAIT) {
+            Ok((0, _)) => return Ok(false),
+            Ok((n, _)) => client.recv_buf.extend_from_slice(&chunk[..n]),
+            Err(Errno::AGAIN) => break,
+            Err(Errno::INTR) => continue,
+            Err(Errno::CONNRESET) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        ensure!(
+            client.recv_buf.len() <= MAX_MESSAGE_SIZE,
+            "Client sent an oversized message"
+        );
+    }
+    handle_messages(client)?;
+    Ok(true)
+}
+
+/// Handle any complete frames in the client's receive buffer
+pub fn handle_messages(client: &mut Client) -> Result<()> {
+    let buf = std::mem::take(&mut client.recv_buf);
+    let mut consumed = 0;
+    while let Ok(frame) = Frame::from_slice(&buf[consumed..]) {
+        consumed += frame.bytes.len();
+        handle_frame(client, frame)?;
+    }
+    client.recv_buf = buf;
+    client.recv_buf.drain(..consumed);
+    Ok(())
+}
+
+fn handle_frame(client: &mut Client, frame: Frame<&[u8]>) -> Result<()> {
+    ensure!(frame.mask().is_some(), "Client sent an unmasked frame");
+    ensure!(frame.fin(), "Fragmented messages aren't supported");
+    client.last_heard = Instant::now();
+    client.ping_pending = false;
+    if client.closing {
+        return Ok(()); // Too late
+    }
+    let payload = frame.unmasked_payload();
+    match frame.opcode() {
+        OpCode::Text => (),
+        OpCode::Ping => {
+            trace!("Ping!");
+            send_control_frame(client, OpCode::Pong, &payload);
+            return Ok(());
+        }
+        OpCode::Pong => return Ok(()),
+        OpCode::Close => {
+            // Echo the status code back, and then hang up
+            info!("Client sent a close frame");
+            send_control_frame(client, OpCode::Close, &payload[..payload.len().min(2)]);
+            client.closing = true;
+            return Ok(());
+        }
+        x => bail!("Unexpected opcode: {x:?}"),
+    }
+    // A bad message isn't worth disconnecting over
+    let options = parse_message(&payload).and_then(|options| {
+        crate::compression::check(client.compression, &options)?;
+        Ok(options)
+    });
+    match options {
+        Ok(options) => {
+            debug!(?options, "Received an options update");
+            client.pending_options = Some(options);
+        }
+        Err(e) => warn!("Bad message from client: {e:#}"),
+    }
+    Ok(())
+}
+
+pub fn send_control_frame(client: &mut Client, opcode: OpCode, payload: &[u8]) {
+    let frame = Frame::new(opcode, payload);
+    client.outbox.extend_from_slice(&frame.bytes);
+}
+
+/// Start closing the connection.  The close frame goes out at the next frame
+/// boundary, or the socket is shut down after `CLOSE_TIMEOUT` if it doesn't.
+pub fn close(client: &mut Client, code: u16, reason: &str) {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    send_control_frame(client, OpCode::Close, &payload);
+    client.closing = true;
+    client.close_deadline = Some(Instant::now() + CLOSE_TIMEOUT);
+}
+
+/// Shut the socket down if the client hasn't taken our close frame in time
+pub fn check_close_deadline(client: &mut Client) {
+    if client
+        .close_deadline
+        .is_some_and(|x| Instant::now() >= x && !client.outbox.is_empty())
+    {
+        warn!("Couldn't send the close frame in time; disconnecting");
+        client.close_deadline = None;
+        client.outbox.clear();
+        // The poll will notice and remove the client
+        let _ = rustix::net::shutdown(&client.conn, Shutdown::Both);
+    }
+}
+
+/// Send as much of the outbox as we can without blocking.  Must only be
+/// called when the pipe is empty and everything in it ended at a frame
+/// boundary.
+pub fn flush_outbox(client: &mut Client) {
+    let flags = SendFlags::DONTWAIT | SendFlags::NOSIGNAL;
+    match rustix::net::send(&client.conn, &client.outbox, flags) {
+        Ok(n) => drop(client.outbox.drain(..n)),
+        Err(Errno::AGAIN) => (),
+        Err(e) => {
+            // The poll will notice if the client has gone away
+            debug!("Couldn't send control frames: {e}");
+            client.outbox.clear();
+        }
+    }
+    if client.closing && client.outbox.is_empty() {
+        // That was our close frame.  Shutting down the socket wakes up the
+        // poll, which then removes the client.
+        let _ = rustix::net::shutdown(&client.conn, Shutdown::Both);
+    }
+}
+
+/// Ping the client if it's been quiet, or give up on it if it's been quiet
+/// for too long
+pub fn keep_alive(client: &mut Client, timeout: Duration) {
+    if client.closing {
+        return;
+    }
+    let silence = client.last_heard.elapsed();
+    if silence > timeout {
+        warn!("Haven't heard from the client in {silence:?}; disconnecting");
+        client.closing = true;
+        client.outbox.clear();
+        // The poll will notice and remove the client
+        let _ = rustix::net::shutdown(&client.conn, Shutdown::Both);
+    } else if silence > timeout / 2 && !client.ping_pending {
+        trace!("Pinging client");
+        send_control_frame(client, OpCode::Ping, &[]);
+        client.ping_pending = true;
+    }
+}
+
+fn parse_message(payload: &[u8]) -> Result<Options> {
+    let payload = std::str::from_utf8(payload)?;
+    ensure!(gjson::valid(payload), "Invalid JSON");
+    let msg_type = gjson::get(payload, "type");
+    match msg_type.str() {
+        "options_update" => (),
+        x => bail!("Unknown message type: {x:?}"),
+    }
+    let strings = |path| -> Result<Vec<String>> {
+        let x = gjson::get(payload, path);
+        match x.kind() {
+            gjson::Kind::Null => Ok(vec![]),
+            gjson::Kind::Array => x
+                .array()
+                .iter()
+                .map(|x| {
+                    ensure!(x.kind() == gjson::Kind::String, "{path}: Expected strings");
+                    Ok(x.str().to_owned())
+                })
+                .collect(),
+            _ => bail!("{path}: Expected an array"),
+        }
+    };
+    let options = Options {
+        wanted_collections: strings("payload.wantedCollections")?,
+        wanted_dids: strings("payload.wantedDids")?,
+        max_message_size_bytes: gjson::get(payload, "payload.maxMessageSizeBytes").u64() as usize,
+    };
+    options.validate()?;
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_update() {
+        let msg = br#"{"type":"options_update","payload":{
+            "wantedCollections":["app.bsky.feed.post"],
+            "wantedDids":[],
+            "maxMessageSizeBytes":1000}}"#;
+        let options = parse_message(msg).unwrap();
+        assert_eq!(options.wanted_collections, ["app.bsky.feed.post"]);
+        assert!(options.wanted_dids.is_empty());
+        assert_eq!(options.max_message_size(), 1000);
+
+        let options = parse_message(br#"{"type":"options_update","payload":{}}"#).unwrap();
+        assert!(options.wanted_collections.is_empty());
+        assert_eq!(options.max_message_size(), u64::MAX);
+
+        assert!(parse_message(br#"{"type":"hello"}"#).is_err());
+        assert!(parse_message(br#"{"type":"options_update","#).is_err());
+        let msg = br#"{"type":"options_update","payload":{"wantedCollections":["*"]}}"#;
+        assert!(parse_message(msg).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! The status page
+//!
+//! A self-contained HTML page, served at `/` alongside the metrics.  The page
+//! itself is static: it polls `/status.json` and fills in the numbers.
+//!
+//! Like the metrics, the per-client details belong to the runloop, so it
+//! publishes a snapshot every so often (see [`record_clients`]).  We also keep
+//! the last few warnings and errors from the log, via a `tracing` layer.
+//!
+//! The metrics port may well be reachable by anyone, so the page doesn't show
+//! the clients' addresses.  Admins can get those from the admin socket.
+
+use crate::upstream::{ACTIVE_UPSTREAM, INDEX, SWITCH_HISTORY, Timestamp, UPSTREAM_LAG_US};
+use crate::{Client, ClientId, Feed};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Write};
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::FormattedFields;
+use tracing_subscriber::fmt::format::DefaultFields;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use url::Url;
+
+pub const PAGE: &str = include_str!("dashboard.html");
+
+/// How often the runloop publishes its snapshot of the clients
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many warnings and errors we remember
+const MAX_RECENT_ERRORS: usize = 50;
+
+static CLIENT_TABLE: Mutex<Vec<ClientStatus>> = Mutex::new(Vec::new());
+static RECENT_ERRORS: Mutex<VecDeque<LogLine>> = Mutex::new(VecDeque::new());
+
+/// One row of the client table
+struct ClientStatus {
+    id: ClientId,
+    /// Only for feeds which come from `jetrelay.dat`
+    offset: Option<u64>,
+    lag: Duration,
+    bytes_sent: u64,
+    feed: String,
+}
+
+pub fn record_clients(clients: &HashMap<ClientId, Client>) {
+    let mut table: Vec<ClientStatus> = clients
+        .iter()
+        .map(|(id, client)| ClientStatus {
+            id: *id,
+            offset: matches!(client.feed, Feed::All | Feed::Dids(_)).then_some(client.offset),
+            lag: client.lag,
+            bytes_sent: client.bytes_sent,
+            feed: client.feed.to_string(),
+        })
+        .collect();
+    table.sort_by_key(|x| x.id);
+    *CLIENT_TABLE.lock().unwrap() = table;
+}
+
+/// The status of the whole relay, as JSON.  `upstreams` is `UPSTREAM_URL`.
+pub fn status_json(upstreams: &[Url], merge: bool) -> String {
+    let mut out = String::new();
+    let urls: Vec<String> = upstreams.iter().map(|x| json_str(x.as_str())).collect();
+    let active = match merge {
+        true => "null".to_owned(),
+        false => ACTIVE_UPSTREAM.load(Ordering::Relaxed).to_string(),
+    };
+    let switches: Vec<String> = SWITCH_HISTORY
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|x| {
+            format!(
+                r#"{{"time_us":{},"from":{},"to":{},"reason":{}}}"#,
+                x.time.0,
+                x.from,
+                x.to,
+                json_str(x.reason),
+            )
+        })
+        .collect();
+    let (oldest, newest) = {
+        let index = INDEX.lock().unwrap();
+        let ts = |x: Option<(&Timestamp, &u64)>| x.map_or("null".to_owned(), |x| x.0.0.to_string());
+        (ts(index.first_key_value()), ts(index.last_key_value()))
+    };
+    write!(
+        out,
+        r#"{{"upstream":{{"urls":[{}],"active":{active},"lag_s":{},"frames":{},"bytes":{},"switches":[{}]}},"#,
+        urls.join(","),
+        UPSTREAM_LAG_US.load(Ordering::Relaxed) as f64 / 1e6,
+        crate::metrics::UPSTREAM_FRAMES.load(Ordering::Relaxed),
+        crate::metrics::UPSTREAM_BYTES.load(Ordering::Relaxed),
+        switches.join(","),
+    )
+    .unwrap();
+    write!(
+        out,
+        r#""retention":{{"oldest_us":{oldest},"newest_us":{newest}}},"#
+    )
+    .unwrap();
+
+    let clients = CLIENT_TABLE.lock().unwrap();
+    let rows: Vec<String> = clients
+        .iter()
+        .map(|x| {
+            format!(
+                r#"{{"id":{},"offset":{},"lag_s":{},"bytes_sent":{},"feed":{}}}"#,
+                x.id,
+                x.offset.map_or("null".to_owned(), |x| x.to_string()),
+                x.lag.as_secs_f64(),
+                x.bytes_sent,
+                json_str(&x.feed),
+            )
+        })
+        .collect();
+    write!(out, r#""clients":[{}],"#, rows.join(",")).unwrap();
+    drop(clients);
+
+    let errors = RECENT_ERRORS.lock().unwrap();
+    let rows: Vec<String> = errors
+        .iter()
+        .rev()
+        .map(|x| {
+            format!(
+                r#"{{"time_us":{},"level":"{}","message":{}}}"#,
+                x.time.0,
+                x.level,
+                json_str(&x.message),
+            )
+        })
+        .collect();
+    write!(out, r#""errors":[{}]}}"#, rows.join(",")).unwrap();
+    out
+}
+
+fn json_str(x: &str) -> String {
+    let mut out = String::with_capacity(x.len() + 2);
+    out.push('"');
+    for c in x.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+struct LogLine {
+    time: Timestamp,
+    level: Level,
+    message: String,
+}
+
+/// Keeps the last few warnings and errors for the status page
+pub struct RecentErrors;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for RecentErrors {
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let level = *event.metadata().level();
+        if level > Level::WARN {
+            return;
+        }
+        // Prefix the message with the spans' fields (eg. the client ID), the
+        // way the fmt layer does
+        let mut message = String::new();
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|x| x.from_root())
+        {
+            let extensions = span.extensions();
+            match extensions.get::<FormattedFields<DefaultFields>>() {
+                Some(fields) if !fields.is_empty() => write!(message, "{fields}: ").unwrap(),
+                _ => (),
+            }
+        }
+        event.record(&mut MessageVisitor(&mut message));
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut errors = RECENT_ERRORS.lock().unwrap();
+        if errors.len() >= MAX_RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(LogLine {
+            time: Timestamp(time.as_micros() as u64),
+            level,
+            message,
+        });
+    }
+}
+
+struct MessageVisitor<'a>(&'a mut String);
+
+impl Visit for MessageVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.0, "{value:?}").unwrap();
+        } else {
+            write!(self.0, " {}={value:?}", field.name()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(json_str("abc"), r#""abc""#);
+        assert_eq!(json_str("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+}
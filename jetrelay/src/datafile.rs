@@ -0,0 +1,141 @@
+use crate::upstream::{FrameSizes, Timestamp};
+use anyhow::Result;
+use rustix::fs::FallocateFlags;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::*;
+
+/// The timestamp of the last frame which has been completely written (to all
+/// files).  A frame which was written to one data file can't be sent until
+/// this has caught up with it; otherwise a client might receive it before some
+/// older frame from a different file.
+pub static WATERMARK: AtomicU64 = AtomicU64::new(0);
+
+/// An append-only file of websocket frames, with its own indices.  These are
+/// the files we write alongside `jetrelay.dat`.
+pub struct DataFile {
+    pub name: String,
+    pub file: File,
+    pub len: AtomicU64,
+    /// Everything before this offset has been dropped
+    pub low_water: AtomicU64,
+    /// The timestamp of the newest frame which has been dropped
+    pub dropped_up_to: AtomicU64,
+    /// Maps each timestamp to the offset of the _first_ frame with that
+    /// timestamp, so that a range of offsets always covers whole frames
+    pub index: Mutex<BTreeMap<Timestamp, u64>>,
+    /// The inverse of `index`
+    timestamps: Mutex<BTreeMap<u64, Timestamp>>,
+    pub sizes: Mutex<FrameSizes>,
+}
+
+impl fmt::Debug for DataFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DataFile").field(&self.name).finish()
+    }
+}
+
+impl DataFile {
+    /// Any existing file is left over from a previous run.  We throw it away:
+    /// everything in it gets re-written from `jetrelay.dat`.
+    pub fn create(dir: &Path, name: &str) -> Result<DataFile> {
+        let path = dir.join(format!("jetrelay.{name}.dat"));
+        debug!("Creating a file at {}", path.display());
+        // Replace the file rather than truncating it, in case an older
+        // process is still reading it (see `crate::upgrade`)
+        match std::fs::remove_file(&path) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+        let file = File::options()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(path)?;
+        Ok(DataFile {
+            name: name.to_owned(),
+            file,
+            len: AtomicU64::new(0),
+            low_water: AtomicU64::new(0),
+            dropped_up_to: AtomicU64::new(0),
+            index: Mutex::new(BTreeMap::new()),
+            timestamps: Mutex::new(BTreeMap::new()),
+            sizes: Mutex::new(FrameSizes::new()),
+        })
+    }
+
+    /// The first frame with a timestamp strictly greater than `after`
+    pub fn next_frame(&self, after: Timestamp) -> Option<(Timestamp, u64)> {
+        let index = self.index.lock().unwrap();
+        let mut iter = index.range((Bound::Excluded(after), Bound::Unbounded));
+        iter.next().map(|(ts, offset)| (*ts, *offset))
+    }
+
+    /// Like `crate::upstream::timestamp_before`, but for this file.  `offset`
+    /// doesn't have to be on a frame boundary.
+    pub fn timestamp_before(&self, offset: u64) -> Timestamp {
+        let timestamps = self.timestamps.lock().unwrap();
+        if let Some(ts) = timestamps.get(&offset) {
+            return *ts - Duration::from_micros(1);
+        }
+        match timestamps.range(..offset).next_back() {
+            Some((_, ts)) => *ts,
+            None => Timestamp(self.dropped_up_to.load(Ordering::Acquire)),
+        }
+    }
+
+    /// The index entries are written before the length is updated, so anyone
+    /// who sees the new length will also see the new index entries.
+    pub fn append(&self, timestamp: Timestamp, bytes: &[u8]) -> Result<()> {
+        let offset = self.len.load(Ordering::Relaxed);
+        (&self.file).write_all(bytes)?;
+        if let Entry::Vacant(x) = self.index.lock().unwrap().entry(timestamp) {
+            x.insert(offset);
+            self.timestamps.lock().unwrap().insert(offset, timestamp);
+        }
+        self.sizes
+            .lock()
+            .unwrap()
+            .insert(offset, bytes.len() as u64);
+        self.len.fetch_add(bytes.len() as u64, Ordering::Release);
+        Ok(())
+    }
+
+    /// Drop index entries older than `ts`, and publish the new low-water mark.
+    /// The data stays in the file until `punch_hole`.
+    pub fn drop_old_data(&self, ts: Timestamp) {
+        let mut index = self.index.lock().unwrap();
+        let mut x = index.split_off(&ts);
+        std::mem::swap(&mut x, &mut *index);
+        std::mem::drop(index);
+        if let Some((last_ts, offset)) = x.last_key_value() {
+            trace!("{}: Dropping data up to offset={offset}", self.name);
+            self.low_water.store(*offset, Ordering::Release);
+            self.dropped_up_to.store(last_ts.0, Ordering::Release);
+            let mut timestamps = self.timestamps.lock().unwrap();
+            *timestamps = timestamps.split_off(offset);
+            drop(timestamps);
+            self.sizes.lock().unwrap().drop_old_data(*offset);
+        }
+    }
+
+    /// Deallocate the part of the file before the low-water mark
+    pub fn punch_hole(&self) -> Result<()> {
+        let offset = self.low_water.load(Ordering::Acquire);
+        if offset > 0 {
+            let flags = FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE;
+            rustix::fs::fallocate(&self.file, flags, 0, offset)?;
+            crate::metrics::HOLE_PUNCHES.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
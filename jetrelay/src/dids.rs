@@ -0,0 +1,157 @@
+//! Filtering by DID
+//!
+//! Having a file per DID isn't feasible, so instead we keep an in-memory index
+//! from each DID to the locations of its frames in `jetrelay.dat`.  Clients
+//! which specify `wantedDids` are sent just those frames, one splice per frame.
+//! Clients look up their frames a batch at a time, so that the upstream copier
+//! isn't kept waiting for the index while they go through their DIDs.
+
+use crate::collections::CollectionFilter;
+use crate::datafile::DataFile;
+use crate::upstream::payload_len;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, LazyLock, Mutex};
+use tracing::*;
+
+pub static DID_INDEX: LazyLock<Mutex<DidIndex>> = LazyLock::new(Mutex::default);
+
+/// How many frames a client looks up at once
+const BATCH_SIZE: usize = 256;
+
+/// The location of a frame within `jetrelay.dat`
+#[derive(Debug, Clone)]
+pub struct FrameLoc {
+    pub offset: u64,
+    pub len: u64,
+    /// The collection file the frame was also written to.  `None` if the
+    /// event had a bad collection name.
+    pub collection: Option<Arc<DataFile>>,
+}
+
+/// Frames are pushed in file order, so each DID's list is sorted by offset
+#[derive(Debug, Default)]
+pub struct DidIndex(HashMap<String, VecDeque<FrameLoc>>);
+
+impl DidIndex {
+    /// Must be called _before_ the file length is updated, so that anyone who
+    /// sees the new length will also see the new index entry
+    pub fn push(&mut self, did: &str, loc: FrameLoc) {
+        match self.0.get_mut(did) {
+            Some(locs) => locs.push_back(loc),
+            None => {
+                self.0.insert(did.to_owned(), VecDeque::from([loc]));
+            }
+        }
+    }
+
+    /// The number of DIDs we have frames for
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Forget about frames which start before `offset`
+    pub fn drop_old_data(&mut self, offset: u64) {
+        self.0.retain(|_, locs| {
+            while locs.front().is_some_and(|x| x.offset < offset) {
+                locs.pop_front();
+            }
+            !locs.is_empty()
+        });
+        debug!("DID index now covers {} DIDs", self.0.len());
+    }
+
+    /// The first `BATCH_SIZE` frames in the range `from..to` which the filter
+    /// wants, and whose payloads are no bigger than `limit`.  Also returns how
+    /// far we got: every wanted frame before that is in the batch.
+    fn next_frames(
+        &self,
+        filter: &DidFilter,
+        from: u64,
+        to: u64,
+        limit: u64,
+    ) -> (VecDeque<FrameLoc>, u64) {
+        let mut batch = vec![];
+        for did in &filter.dids {
+            let Some(locs) = self.0.get(did) else {
+                continue;
+            };
+            let i = locs.partition_point(|x| x.offset < from);
+            let wanted = locs
+                .range(i..)
+                .take_while(|x| x.offset < to)
+                .filter(|x| payload_len(x.len) <= limit)
+                .filter(|x| filter.wants_collection(x.collection.as_deref()))
+                .take(BATCH_SIZE);
+            batch.extend(wanted.cloned());
+        }
+        batch.sort_unstable_by_key(|x| x.offset);
+        if batch.len() > BATCH_SIZE {
+            batch.truncate(BATCH_SIZE);
+            let scanned_to = batch.last().unwrap().offset + 1;
+            return (batch.into(), scanned_to);
+        }
+        (batch.into(), to)
+    }
+}
+
+#[derive(Debug)]
+pub struct DidFilter {
+    dids: HashSet<String>,
+    /// If the client also specified `wantedCollections`, then frames must
+    /// match both
+    collections: Option<CollectionFilter>,
+    /// The end of the frame we're currently sending
+    pub run_end: Option<u64>,
+    /// The wanted frames we've looked up, but haven't sent yet
+    upcoming: VecDeque<FrameLoc>,
+    /// We've looked up all the wanted frames before this offset
+    scanned_to: u64,
+}
+
+impl fmt::Display for DidFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} DIDs", self.dids.len())?;
+        if let Some(collections) = &self.collections {
+            write!(f, " in {collections}")?;
+        }
+        Ok(())
+    }
+}
+
+impl DidFilter {
+    pub fn new(dids: &[String], collections: Option<CollectionFilter>) -> DidFilter {
+        DidFilter {
+            dids: dids.iter().cloned().collect(),
+            collections,
+            run_end: None,
+            upcoming: VecDeque::new(),
+            scanned_to: 0,
+        }
+    }
+
+    fn wants_collection(&self, collection: Option<&DataFile>) -> bool {
+        match &self.collections {
+            None => true,
+            Some(filter) => collection.is_some_and(|x| filter.matches(&x.name)),
+        }
+    }
+
+    /// Find the next frame to send, starting at `offset`.  Returns the range
+    /// of the frame, or `None` if there's nothing wanted before `file_len`.
+    pub fn next_frame(&mut self, offset: u64, file_len: u64, limit: u64) -> Option<(u64, u64)> {
+        // The client may have skipped ahead
+        while self.upcoming.front().is_some_and(|x| x.offset < offset) {
+            self.upcoming.pop_front();
+        }
+        let from = self.scanned_to.max(offset);
+        if self.upcoming.is_empty() && from < file_len {
+            let index = DID_INDEX.lock().unwrap();
+            (self.upcoming, self.scanned_to) = index.next_frames(self, from, file_len, limit);
+        }
+        let loc = self.upcoming.pop_front()?;
+        let end = loc.offset + loc.len;
+        self.run_end = Some(end);
+        Some((loc.offset, end))
+    }
+}
@@ -1,64 +1,404 @@
+use crate::ClientId;
+use crate::collections::CollectionFilter;
+use crate::limits::{Cidr, Limits, Permit, Rejection};
+use crate::metrics::HandshakeOutcome;
+use crate::policy::SlowConsumer;
+use crate::proxy::Parsed;
 use crate::upstream::Timestamp;
 use anyhow::{Result, anyhow, ensure};
+use rustix::event::PollFlags;
+use rustix::io::Errno;
+use rustix::net::{RecvFlags, SendFlags};
+use std::collections::HashMap;
 use std::io::prelude::*;
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
 use tracing::*;
 
+/// Same limit as the official jetstream server
+const MAX_WANTED_DIDS: usize = 10_000;
+
+/// Clients which take longer than this to complete the handshake are dropped
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most connections we'll have part-way through the handshake at once.
+/// Any more are hung up on straight away.
+const MAX_HANDSHAKES: usize = 1000;
+
 #[derive(Debug)]
 pub struct ClientConfig {
     pub cursor: Option<Timestamp>,
+    pub options: Options,
+    pub compression: Compression,
+    pub require_hello: bool,
+    /// Overrides for the deployment's slow consumer policy
+    pub slow_consumer: SlowConsumer,
+}
+
+/// The options which a client can change mid-stream, by sending an
+/// `options_update` message
+#[derive(Debug, Clone, Default)]
+pub struct Options {
     pub wanted_collections: Vec<String>,
     pub wanted_dids: Vec<String>,
     pub max_message_size_bytes: usize,
-    pub compress: bool,
-    pub require_hello: bool,
+}
+
+impl Options {
+    /// Check these up-front, so we can reject bad options before they're used
+    pub fn validate(&self) -> Result<()> {
+        CollectionFilter::new(&self.wanted_collections)?;
+        ensure!(
+            self.wanted_dids.len() <= MAX_WANTED_DIDS,
+            "Too many wanted DIDs: {}",
+            self.wanted_dids.len()
+        );
+        Ok(())
+    }
+
+    /// Like the official jetstream server, we treat zero as "no limit"
+    pub fn max_message_size(&self) -> u64 {
+        match self.max_message_size_bytes {
+            0 => u64::MAX,
+            x => u64::try_from(x).unwrap_or(u64::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// `compress=true`
+    Zstd,
+    /// `fsst=true`
+    Fsst,
 }
 
 impl ClientConfig {
-    fn from_query_params(params: &str) -> anyhow::Result<Self> {
+    pub fn from_query_params(params: &str) -> anyhow::Result<Self> {
         let mut config = Self {
             cursor: None,
-            wanted_collections: vec![],
-            wanted_dids: vec![],
-            max_message_size_bytes: usize::MAX,
-            compress: false,
+            options: Options {
+                max_message_size_bytes: usize::MAX,
+                ..Options::default()
+            },
+            compression: Compression::None,
             require_hello: false,
+            slow_consumer: SlowConsumer::default(),
         };
         for query in params.split('&').filter(|x| !x.is_empty()) {
             let (key, val) = query.split_once('=').unwrap_or((query, ""));
+            let options = &mut config.options;
             match key {
                 "cursor" => config.cursor = Some(Timestamp(val.parse()?)),
-                "wantedCollections" => config.wanted_collections.push(val.to_owned()),
-                "wantedDids" => config.wanted_dids.push(val.to_owned()),
+                "wantedCollections" => options.wanted_collections.push(val.to_owned()),
+                "wantedDids" => options.wanted_dids.push(val.to_owned()),
                 "maxMessageSizeBytes" => {
-                    config.max_message_size_bytes = config.max_message_size_bytes.min(val.parse()?)
+                    options.max_message_size_bytes =
+                        options.max_message_size_bytes.min(val.parse()?)
                 }
-                "compress" => config.compress = true,
-                "requireHello" => config.require_hello = true,
+                "compress" if val.parse()? => config.compression = Compression::Zstd,
+                "fsst" if val.parse()? => config.compression = Compression::Fsst,
+                "compress" | "fsst" => (),
+                "requireHello" => config.require_hello = val.parse()?,
+                "slowConsumerPolicy" => config.slow_consumer.action = Some(val.parse()?),
+                "maxLagSeconds" => {
+                    config.slow_consumer.max_lag = Some(Duration::from_secs(val.parse()?))
+                }
+                "maxLagBytes" => config.slow_consumer.max_lag_bytes = Some(val.parse()?),
                 _ => warn!("Unknown query param: {key}"),
             }
         }
+        // Check these now, so we can reject the handshake
+        config.options.validate()?;
         Ok(config)
     }
+
+    /// The inverse of `from_query_params`.  Returns `None` if one of the values
+    /// contains a '&', and so can't be represented.
+    pub fn to_query_params(&self) -> Option<String> {
+        let mut params = vec![];
+        if let Some(cursor) = self.cursor {
+            params.push(format!("cursor={}", cursor.0));
+        }
+        for x in &self.options.wanted_collections {
+            params.push(format!("wantedCollections={x}"));
+        }
+        for x in &self.options.wanted_dids {
+            params.push(format!("wantedDids={x}"));
+        }
+        params.push(format!(
+            "maxMessageSizeBytes={}",
+            self.options.max_message_size_bytes
+        ));
+        match self.compression {
+            Compression::None => (),
+            Compression::Zstd => params.push("compress=true".to_owned()),
+            Compression::Fsst => params.push("fsst=true".to_owned()),
+        }
+        if self.require_hello {
+            params.push("requireHello=true".to_owned());
+        }
+        if let Some(x) = self.slow_consumer.action {
+            params.push(format!("slowConsumerPolicy={x}"));
+        }
+        if let Some(x) = self.slow_consumer.max_lag {
+            params.push(format!("maxLagSeconds={}", x.as_secs()));
+        }
+        if let Some(x) = self.slow_consumer.max_lag_bytes {
+            params.push(format!("maxLagBytes={x}"));
+        }
+        if params.iter().any(|x| x.contains('&')) {
+            return None;
+        }
+        Some(params.join("&"))
+    }
+}
+
+/// Accepts new connections, and takes them through the websocket handshake.
+/// All the IO is driven by the runloop; see `crate::io::accept_clients`.
+#[derive(Debug)]
+pub struct Acceptor {
+    pub listener: TcpListener,
+    pub accept_in_flight: bool,
+    /// If accept() fails (eg. because we're out of fds), we wait a bit before
+    /// trying again
+    pub backoff_until: Option<Instant>,
+    pub handshakes: HashMap<ClientId, Handshake>,
+    pub next_client_id: ClientId,
+    /// We're shutting down, so we're not accepting any more connections
+    pub stopped: bool,
+    pub limits: Limits,
+    /// Connections from these addresses may start with a PROXY header
+    pub trusted_proxies: Vec<Cidr>,
 }
 
-// TODO: timeout
-pub fn perform_handshake(conn: &mut TcpStream) -> Result<ClientConfig> {
-    let mut buf = [0; 4096];
-    let mut n = 0;
-    loop {
-        n += conn.read(&mut buf[n..])?;
-        let mut headers = [httparse::EMPTY_HEADER; 16];
-        let mut req = httparse::Request::new(&mut headers);
-        let status = req.parse(&buf[..n])?;
-
-        match status {
-            httparse::Status::Complete(_) => {
-                let (key, query_params) = validate_request(req)?;
-                send_response(conn, key)?;
-                return ClientConfig::from_query_params(query_params);
+impl Acceptor {
+    pub fn new(listener: TcpListener, limits: Limits, trusted_proxies: Vec<Cidr>) -> Acceptor {
+        Acceptor {
+            listener,
+            accept_in_flight: false,
+            backoff_until: None,
+            handshakes: HashMap::new(),
+            next_client_id: 0,
+            stopped: false,
+            limits,
+            trusted_proxies,
+        }
+    }
+
+    /// Returns the ID which the client will have once the handshake is done,
+    /// or `None` if we hung up.  `n_clients` is the number of clients which
+    /// are already connected.
+    ///
+    /// The connection is checked against the limits straight away, unless it
+    /// came through a proxy, in which case we don't know who it's from yet.
+    pub fn start_handshake(&mut self, conn: TcpStream, n_clients: usize) -> Option<ClientId> {
+        if self.handshakes.len() >= MAX_HANDSHAKES {
+            crate::metrics::count_handshake(HandshakeOutcome::Limited);
+            // Not a warning, since there could be a lot of these
+            info!("Too many handshakes in progress; hanging up");
+            return None;
+        }
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+        let _g = info_span!("", client_id).entered();
+        match (conn.peer_addr(), conn.local_addr()) {
+            (Ok(peer_addr), Ok(local_addr)) => info!(
+                %peer_addr,
+                %local_addr,
+                "New client connected",
+            ),
+            _ => info!("New client connected"),
+        }
+        let peer_addr = conn.peer_addr().ok();
+        let proxied = peer_addr.is_some_and(|addr| {
+            let ip = addr.ip();
+            self.trusted_proxies.iter().any(|x| x.contains(ip))
+        });
+        let admission = (!proxied).then(|| {
+            let ip = peer_addr.map(|x| x.ip());
+            self.limits.admit(ip, n_clients + self.n_pending())
+        });
+        self.handshakes.insert(
+            client_id,
+            Handshake::new(conn, peer_addr, proxied, admission),
+        );
+        Some(client_id)
+    }
+
+    /// The handshakes which haven't been turned away.  These count towards
+    /// `MAX_CLIENTS`.
+    pub fn n_pending(&self) -> usize {
+        let rejected = |x: &Handshake| matches!(x.admission, Some(Err(_)));
+        self.handshakes.values().filter(|x| !rejected(x)).count()
+    }
+}
+
+/// A connection which is part-way through the websocket handshake.  Each time
+/// the socket becomes ready, the runloop calls `advance()`.
+#[derive(Debug)]
+pub struct Handshake {
+    pub conn: TcpStream,
+    /// The client must have completed the handshake by this time
+    pub deadline: Instant,
+    pub poll_in_flight: bool,
+    /// The client's address.  If the connection came through a proxy, this is
+    /// the address the proxy told us about.
+    peer_addr: Option<SocketAddr>,
+    /// The connection is from a trusted proxy, and we haven't checked for a
+    /// PROXY header yet
+    expect_proxy_header: bool,
+    /// Whether the limits let the client in.  `None` until we know who the
+    /// client is.  If it was turned away, we still wait for its request, so
+    /// that we can tell it so.
+    admission: Option<Result<Permit, Rejection>>,
+    state: State,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Waiting for the client's request
+    Reading { buf: Box<[u8; 4096]>, n: usize },
+    /// Sending our response
+    Writing {
+        response: Vec<u8>,
+        sent: usize,
+        config: ClientConfig,
+        /// Anything the client sent after the request
+        leftover: Vec<u8>,
+    },
+}
+
+impl Handshake {
+    fn new(
+        conn: TcpStream,
+        peer_addr: Option<SocketAddr>,
+        proxied: bool,
+        admission: Option<Result<Permit, Rejection>>,
+    ) -> Handshake {
+        Handshake {
+            conn,
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            poll_in_flight: false,
+            peer_addr,
+            expect_proxy_header: proxied,
+            admission,
+            state: State::Reading {
+                buf: Box::new([0; 4096]),
+                n: 0,
+            },
+        }
+    }
+
+    /// What the socket needs to be ready for before we can make progress
+    pub fn poll_flags(&self) -> PollFlags {
+        match self.state {
+            State::Reading { .. } => PollFlags::IN,
+            State::Writing { .. } => PollFlags::OUT,
+        }
+    }
+
+    /// Make as much progress as we can without blocking.  Returns true once
+    /// the handshake is complete.  `n_clients` is the number of clients which
+    /// are already connected, or part-way through the handshake.
+    ///
+    /// If the client is turned away by the `limits`, this fails with a
+    /// [`Rejection`] once the client has been told.
+    pub fn advance(&mut self, limits: &mut Limits, n_clients: usize) -> Result<bool> {
+        loop {
+            match &mut self.state {
+                State::Reading { buf, n } => {
+                    match rustix::net::recv(&self.conn, &mut buf[*n..], RecvFlags::DONTWAIT) {
+                        Ok((0, _)) => {
+                            let e = anyhow::Error::from(Errno::CONNRESET);
+                            return Err(e.context("Client hung up during the handshake"));
+                        }
+                        Ok((m, _)) => *n += m,
+                        Err(Errno::AGAIN) => return Ok(false),
+                        Err(Errno::INTR) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                    if self.expect_proxy_header {
+                        match crate::proxy::parse(&buf[..*n])? {
+                            Parsed::Incomplete => {
+                                ensure!(*n < buf.len(), "PROXY header is too big");
+                                continue;
+                            }
+                            Parsed::Absent => (),
+                            Parsed::Header { len, source } => {
+                                buf.copy_within(len..*n, 0);
+                                *n -= len;
+                                if let Some(peer_addr) = source {
+                                    info!(%peer_addr, "Got a PROXY header");
+                                    self.peer_addr = Some(peer_addr);
+                                }
+                            }
+                        }
+                        self.expect_proxy_header = false;
+                    }
+                    let mut headers = [httparse::EMPTY_HEADER; 16];
+                    let mut req = httparse::Request::new(&mut headers);
+                    match req.parse(&buf[..*n])? {
+                        httparse::Status::Complete(len) => {
+                            let (key, query_params) = validate_request(req)?;
+                            let config = ClientConfig::from_query_params(query_params)?;
+                            crate::compression::check(config.compression, &config.options)?;
+                            info!(cursor = config.cursor.map(|x| x.0), "Got handshake request");
+                            let admission = self.admission.get_or_insert_with(|| {
+                                let ip = self.peer_addr.map(|x| x.ip());
+                                limits.admit(ip, n_clients)
+                            });
+                            let response = match admission {
+                                Ok(_) => response(key),
+                                Err(rejection) => rejection.response(),
+                            };
+                            let leftover = buf[len..*n].to_vec();
+                            self.state = State::Writing {
+                                response,
+                                sent: 0,
+                                config,
+                                leftover,
+                            };
+                        }
+                        httparse::Status::Partial => {
+                            ensure!(*n < buf.len(), "Handshake request is too big")
+                        }
+                    }
+                }
+                State::Writing { response, sent, .. } => {
+                    let flags = SendFlags::DONTWAIT | SendFlags::NOSIGNAL;
+                    match rustix::net::send(&self.conn, &response[*sent..], flags) {
+                        Ok(m) => *sent += m,
+                        Err(Errno::AGAIN) => return Ok(false),
+                        Err(Errno::INTR) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                    if *sent == response.len() {
+                        return match &self.admission {
+                            Some(Ok(_)) => Ok(true),
+                            Some(Err(rejection)) => Err((*rejection).into()),
+                            None => unreachable!(),
+                        };
+                    }
+                }
             }
-            httparse::Status::Partial => (), // loop
+        }
+    }
+
+    /// Once `advance()` has returned true, returns the connection, the
+    /// client's address, its config, anything it sent after the request, and
+    /// its place in the connection limits
+    pub fn finish(self) -> (TcpStream, Option<SocketAddr>, ClientConfig, Vec<u8>, Permit) {
+        match (self.state, self.admission) {
+            (
+                State::Writing {
+                    config, leftover, ..
+                },
+                Some(Ok(permit)),
+            ) => (self.conn, self.peer_addr, config, leftover, permit),
+            (State::Writing { .. }, _) => panic!("The client was turned away"),
+            (State::Reading { .. }, _) => panic!("Handshake isn't finished"),
         }
     }
 }
@@ -97,7 +437,7 @@ fn validate_request<'b>(req: httparse::Request<'_, 'b>) -> anyhow::Result<(&'b [
     Ok((key, query_params))
 }
 
-fn send_response(conn: &mut TcpStream, key: &[u8]) -> anyhow::Result<()> {
+fn response(key: &[u8]) -> Vec<u8> {
     let accept = {
         use base64::prelude::*;
         let magic = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
@@ -107,12 +447,54 @@ fn send_response(conn: &mut TcpStream, key: &[u8]) -> anyhow::Result<()> {
         BASE64_STANDARD.encode(sha1_smol::Sha1::from(buf).digest().bytes())
     };
 
-    writeln!(conn, "HTTP/1.1 101 Switching Protocols\r")?;
-    writeln!(conn, "Connection: Upgrade\r")?;
-    writeln!(conn, "Upgrade: websocket\r")?;
-    writeln!(conn, "Server: tailsrv\r")?;
-    writeln!(conn, "Sec-WebSocket-Accept: {accept}\r")?;
-    writeln!(conn, "\r")?;
+    let mut buf = vec![];
+    writeln!(buf, "HTTP/1.1 101 Switching Protocols\r").unwrap();
+    writeln!(buf, "Connection: Upgrade\r").unwrap();
+    writeln!(buf, "Upgrade: websocket\r").unwrap();
+    writeln!(buf, "Server: tailsrv\r").unwrap();
+    writeln!(buf, "Sec-WebSocket-Accept: {accept}\r").unwrap();
+    writeln!(buf, "\r").unwrap();
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_params_round_trip() {
+        let params = "cursor=1725911162329308&wantedCollections=app.bsky.feed.*&\
+                      wantedDids=did:plc:abc&maxMessageSizeBytes=1000&compress=true&\
+                      slowConsumerPolicy=skip&maxLagSeconds=30";
+        let config = ClientConfig::from_query_params(params).unwrap();
+        assert_eq!(config.to_query_params().unwrap(), params);
+    }
 
-    Ok(())
+    #[test]
+    fn boolean_query_params() {
+        let config = ClientConfig::from_query_params("compress=false&requireHello=false").unwrap();
+        assert_eq!(config.compression, Compression::None);
+        assert!(!config.require_hello);
+        let config = ClientConfig::from_query_params("fsst=true").unwrap();
+        assert_eq!(config.compression, Compression::Fsst);
+        assert!(ClientConfig::from_query_params("compress=yes").is_err());
+    }
+
+    #[test]
+    fn limits_at_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = Limits::new(Some(2), Some(1), vec![], None);
+        let mut acceptor = Acceptor::new(listener, limits, vec![]);
+        let mut accept = || {
+            let _conn = TcpStream::connect(addr).unwrap();
+            let (conn, _) = acceptor.listener.accept().unwrap();
+            let id = acceptor.start_handshake(conn, 0).unwrap();
+            matches!(acceptor.handshakes[&id].admission, Some(Ok(_)))
+        };
+        // The first handshake counts towards the per-IP limit before it's done
+        assert!(accept());
+        assert!(!accept());
+        assert_eq!(acceptor.n_pending(), 1);
+    }
 }
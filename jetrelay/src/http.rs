@@ -0,0 +1,99 @@
+//! The HTTP side
+//!
+//! If `METRICS_PORT` is set, we serve [`crate::metrics`] on that port at
+//! `/metrics`, and the status page from [`crate::dashboard`] at `/`.  This is
+//! deliberately simple: it runs on its own thread, well away from the runloop,
+//! and handles one request per connection, one connection at a time.  Scrapers
+//! and the odd human don't need anything fancier.
+
+use anyhow::{Result, ensure};
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tracing::*;
+use url::Url;
+
+/// So that a client which stops sending (or reading) doesn't block everyone
+/// else
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the HTTP server needs to know about the rest of the relay
+pub struct Context {
+    /// `jetrelay.dat`
+    pub file: File,
+    /// `UPSTREAM_URL`
+    pub upstreams: Vec<Url>,
+    /// Whether we're in `UPSTREAM_MODE=merge`
+    pub merge: bool,
+}
+
+/// Runs forever
+pub fn serve(listener: TcpListener, cx: Context) {
+    let _g = info_span!("http server").entered();
+    for conn in listener.incoming() {
+        let conn = match conn {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Couldn't accept a connection: {e}");
+                continue;
+            }
+        };
+        if let Err(e) = handle_request(conn, &cx) {
+            debug!("HTTP request failed: {e:#}");
+        }
+    }
+}
+
+fn handle_request(mut conn: TcpStream, cx: &Context) -> Result<()> {
+    conn.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    conn.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let path = read_request(&mut conn)?;
+    trace!("GET {path}");
+    const TEXT: &str = "text/plain; version=0.0.4";
+    let (status, content_type, body) = match path.as_str() {
+        "/metrics" => ("200 OK", TEXT, crate::metrics::render(&cx.file)?),
+        "/" => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            crate::dashboard::PAGE.to_owned(),
+        ),
+        "/status.json" => (
+            "200 OK",
+            "application/json",
+            crate::dashboard::status_json(&cx.upstreams, cx.merge),
+        ),
+        _ => ("404 Not Found", TEXT, String::new()),
+    };
+    let mut buf = vec![];
+    writeln!(buf, "HTTP/1.1 {status}\r")?;
+    writeln!(buf, "Content-Type: {content_type}\r")?;
+    writeln!(buf, "Content-Length: {}\r", body.len())?;
+    writeln!(buf, "Connection: close\r")?;
+    writeln!(buf, "\r")?;
+    buf.extend_from_slice(body.as_bytes());
+    conn.write_all(&buf)?;
+    Ok(())
+}
+
+/// Returns the path (without the query string)
+fn read_request(conn: &mut TcpStream) -> Result<String> {
+    let mut buf = [0; 4096];
+    let mut n = 0;
+    loop {
+        let m = conn.read(&mut buf[n..])?;
+        ensure!(m != 0, "Client hung up");
+        n += m;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf[..n])? {
+            httparse::Status::Complete(_) => {
+                ensure!(req.method == Some("GET"), "Wrong method");
+                let path = req.path.unwrap_or("/");
+                let path = path.split_once('?').map_or(path, |x| x.0);
+                return Ok(path.to_owned());
+            }
+            httparse::Status::Partial => ensure!(n < buf.len(), "Request is too big"),
+        }
+    }
+}
@@ -1,10 +1,22 @@
-use crate::{Client, ClientId};
-use anyhow::{Result, bail, ensure};
-use rustix::fd::AsRawFd;
+use crate::collections::Run;
+use crate::handshake::{Acceptor, HANDSHAKE_TIMEOUT, Handshake};
+use crate::limits::Rejection;
+use crate::metrics::HandshakeOutcome;
+use crate::policy::Policy;
+use crate::upstream::{FRAME_SIZES, FrameSizes};
+use crate::{Client, ClientId, Feed};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use rustix::event::{EventfdFlags, PollFlags};
+use rustix::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
 use rustix::io::Errno;
 use rustix::io_uring::io_uring_user_data;
 use rustix_uring::{cqueue, opcode, squeue, types::Timespec};
 use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use tracing::*;
 
 /// A kind of cookie which you can attach to io_uring submissions, which allows
@@ -15,18 +27,71 @@ enum UserData {
     Timeout,
     FillPipe(ClientId),
     DrainPipe(ClientId),
+    PollConn(ClientId),
+    Accept,
+    PollHandshake(ClientId),
+    HandshakeTimeout(ClientId),
+    Wakeup,
+    Signal,
+    Cancel,
 }
 
-impl From<UserData> for io_uring_user_data {
-    fn from(value: UserData) -> Self {
-        io_uring_user_data::from_u64(match value {
+/// The names of the `UserData` variants, for the metrics
+pub const USER_DATA_KINDS: [&str; 10] = [
+    "timeout",
+    "fill_pipe",
+    "drain_pipe",
+    "poll_conn",
+    "accept",
+    "poll_handshake",
+    "handshake_timeout",
+    "wakeup",
+    "signal",
+    "cancel",
+];
+
+impl UserData {
+    /// The index into `USER_DATA_KINDS`
+    fn kind(&self) -> usize {
+        match self {
+            UserData::Timeout => 0,
+            UserData::FillPipe(_) => 1,
+            UserData::DrainPipe(_) => 2,
+            UserData::PollConn(_) => 3,
+            UserData::Accept => 4,
+            UserData::PollHandshake(_) => 5,
+            UserData::HandshakeTimeout(_) => 6,
+            UserData::Wakeup => 7,
+            UserData::Signal => 8,
+            UserData::Cancel => 9,
+        }
+    }
+
+    fn encode(&self) -> io_uring_user_data {
+        io_uring_user_data::from_u64(match *self {
             UserData::Timeout => 0 << 32,
             UserData::FillPipe(id) => (1 << 32) | id as u64,
             UserData::DrainPipe(id) => (2 << 32) | id as u64,
+            UserData::PollConn(id) => (3 << 32) | id as u64,
+            UserData::Accept => 4 << 32,
+            UserData::PollHandshake(id) => (5 << 32) | id as u64,
+            UserData::HandshakeTimeout(id) => (6 << 32) | id as u64,
+            UserData::Wakeup => 7 << 32,
+            UserData::Signal => 8 << 32,
+            UserData::Cancel => 9 << 32,
         })
     }
 }
 
+impl From<UserData> for io_uring_user_data {
+    fn from(value: UserData) -> Self {
+        // Every submission gets its user data from here, so this is where we
+        // count them
+        crate::metrics::SUBMISSIONS[value.kind()].fetch_add(1, Ordering::Relaxed);
+        value.encode()
+    }
+}
+
 impl TryFrom<io_uring_user_data> for UserData {
     type Error = anyhow::Error;
     fn try_from(value: io_uring_user_data) -> Result<Self, Self::Error> {
@@ -35,11 +100,140 @@ impl TryFrom<io_uring_user_data> for UserData {
             0 => Ok(UserData::Timeout),
             1 => Ok(UserData::FillPipe(value as u32)),
             2 => Ok(UserData::DrainPipe(value as u32)),
+            3 => Ok(UserData::PollConn(value as u32)),
+            4 => Ok(UserData::Accept),
+            5 => Ok(UserData::PollHandshake(value as u32)),
+            6 => Ok(UserData::HandshakeTimeout(value as u32)),
+            7 => Ok(UserData::Wakeup),
+            8 => Ok(UserData::Signal),
+            9 => Ok(UserData::Cancel),
             x => bail!("{value:x}: Unknown user data: {x}"),
         }
     }
 }
 
+/// How long to wait before trying again when accept() fails
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The size of a pipe's buffer (Linux's default)
+const PIPE_CAPACITY: u64 = 64 * 1024;
+
+/// Other threads write to this eventfd to wake up the runloop
+static EVENTFD: OnceLock<OwnedFd> = OnceLock::new();
+/// Set when someone has written to the eventfd, and the runloop hasn't
+/// noticed yet.  Saves us from making a syscall for every frame.
+static WAKEUP_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Wake up the runloop, so it sends new data to clients straight away instead
+/// of waiting for the next timeout
+pub fn wake_runloop() {
+    if WAKEUP_PENDING.swap(true, Ordering::AcqRel) {
+        return; // Someone else already did it
+    }
+    if let Some(fd) = EVENTFD.get() {
+        // This only fails if the counter would overflow, in which case the
+        // runloop is going to wake up anyway
+        let _ = rustix::io::write(fd, &1u64.to_ne_bytes());
+    }
+}
+
+/// The runloop's end of the eventfd
+#[derive(Debug)]
+pub struct Waker {
+    poll_in_flight: bool,
+}
+
+impl Waker {
+    pub fn new() -> Result<Waker> {
+        let fd = rustix::event::eventfd(0, EventfdFlags::CLOEXEC | EventfdFlags::NONBLOCK)?;
+        EVENTFD
+            .set(fd)
+            .map_err(|_| anyhow!("Waker was initialised twice"))?;
+        Ok(Waker {
+            poll_in_flight: false,
+        })
+    }
+
+    /// Make sure we're listening for wakeups.  The poll is multishot, so this
+    /// only needs to do something the first time (or if the poll fails).
+    pub fn arm(&mut self, sqes: &mut Vec<squeue::Entry>) {
+        if !self.poll_in_flight {
+            let fd = rustix_uring::types::Fd(EVENTFD.get().unwrap().as_raw_fd());
+            let flags = u32::from(PollFlags::IN.bits());
+            let sqe = opcode::PollAdd::new(fd, flags)
+                .multi(true)
+                .build()
+                .user_data(UserData::Wakeup);
+            sqes.push(sqe);
+            self.poll_in_flight = true;
+        }
+    }
+}
+
+/// SIGTERM, SIGINT, and SIGUSR2 are delivered to a signalfd, which the runloop
+/// polls.  See [`crate::shutdown`] and [`crate::upgrade`].
+#[derive(Debug)]
+pub struct Signals {
+    fd: OwnedFd,
+    poll_in_flight: bool,
+    /// Signals which have arrived, but the runloop hasn't dealt with yet
+    pub received: Vec<u32>,
+}
+
+impl Signals {
+    /// Blocks the signals, so that they go to the signalfd instead.  Must be
+    /// called before any other threads are started, so that they inherit the
+    /// signal mask.
+    pub fn new() -> Result<Signals> {
+        // SAFETY: These are all plain syscalls, and we check the results
+        let fd = unsafe {
+            let mut set: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, libc::SIGTERM);
+            libc::sigaddset(&mut set, libc::SIGINT);
+            libc::sigaddset(&mut set, libc::SIGUSR2);
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            ensure!(ret == 0, std::io::Error::from_raw_os_error(ret));
+            let fd = libc::signalfd(-1, &set, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK);
+            ensure!(fd >= 0, std::io::Error::last_os_error());
+            OwnedFd::from_raw_fd(fd)
+        };
+        Ok(Signals {
+            fd,
+            poll_in_flight: false,
+            received: vec![],
+        })
+    }
+
+    /// Like [`Waker::arm`]
+    pub fn arm(&mut self, sqes: &mut Vec<squeue::Entry>) {
+        if !self.poll_in_flight {
+            let fd = rustix_uring::types::Fd(self.fd.as_raw_fd());
+            let flags = u32::from(PollFlags::IN.bits());
+            let sqe = opcode::PollAdd::new(fd, flags)
+                .multi(true)
+                .build()
+                .user_data(UserData::Signal);
+            sqes.push(sqe);
+            self.poll_in_flight = true;
+        }
+    }
+
+    fn read(&mut self) {
+        // The signal number is the first field of `signalfd_siginfo`
+        let mut buf = [0; std::mem::size_of::<libc::signalfd_siginfo>()];
+        while let Ok(n) = rustix::io::read(&self.fd, &mut buf) {
+            if n < 4 {
+                break;
+            }
+            self.received
+                .push(u32::from_ne_bytes(buf[..4].try_into().unwrap()));
+        }
+    }
+}
+
+/// The runloop wakes up at least this often, even if nothing happens.  This is
+/// mainly for the sake of keepalives.
 pub fn timeout() -> squeue::Entry {
     const RUNLOOP_TIMEOUT: Timespec = Timespec::new().nsec(100_000_000); // 100 ms
     opcode::Timeout::new(&RUNLOOP_TIMEOUT)
@@ -57,6 +251,21 @@ fn fill_pipe(client_id: ClientId, client: &mut Client, len: u32) -> squeue::Entr
         .user_data(UserData::FillPipe(client_id))
 }
 
+fn fill_pipe_from_run(
+    client_id: ClientId,
+    pipe_wtr: &OwnedFd,
+    run: &Run,
+    len: u32,
+) -> squeue::Entry {
+    let fd_in = rustix_uring::types::Fd(run.file.file.as_raw_fd());
+    let fd_out = rustix_uring::types::Fd(pipe_wtr.as_raw_fd());
+    let off_in = i64::try_from(run.offset).unwrap();
+    let off_out = -1; // Pipes don't have offsets
+    opcode::Splice::new(fd_in, off_in, fd_out, off_out, len)
+        .build()
+        .user_data(UserData::FillPipe(client_id))
+}
+
 fn drain_pipe(client_id: ClientId, client: &mut Client) -> squeue::Entry {
     let fd_in = rustix_uring::types::Fd(client.pipe_rdr.as_raw_fd());
     let fd_out = rustix_uring::types::Fd(client.conn.as_raw_fd());
@@ -68,6 +277,85 @@ fn drain_pipe(client_id: ClientId, client: &mut Client) -> squeue::Entry {
         .user_data(UserData::DrainPipe(client_id))
 }
 
+/// Wait for the client to send us something
+fn poll_conn(client_id: ClientId, client: &mut Client) -> squeue::Entry {
+    let fd = rustix_uring::types::Fd(client.conn.as_raw_fd());
+    let flags = u32::from(PollFlags::IN.bits());
+    opcode::PollAdd::new(fd, flags)
+        .build()
+        .user_data(UserData::PollConn(client_id))
+}
+
+/// Accept new connections.  This is multishot, so it stays armed until it
+/// fails.
+fn accept(listener: &TcpListener) -> squeue::Entry {
+    let fd = rustix_uring::types::Fd(listener.as_raw_fd());
+    opcode::AcceptMulti::new(fd)
+        .build()
+        .user_data(UserData::Accept)
+}
+
+/// Wait for a client which is mid-handshake, but not forever
+fn poll_handshake(client_id: ClientId, handshake: &Handshake) -> [squeue::Entry; 2] {
+    const TIMEOUT: Timespec = Timespec::new().sec(HANDSHAKE_TIMEOUT.as_secs());
+    let fd = rustix_uring::types::Fd(handshake.conn.as_raw_fd());
+    let flags = u32::from(handshake.poll_flags().bits());
+    let poll = opcode::PollAdd::new(fd, flags)
+        .build()
+        .flags(squeue::Flags::IO_LINK)
+        .user_data(UserData::PollHandshake(client_id));
+    let timeout = opcode::LinkTimeout::new(&TIMEOUT)
+        .build()
+        .user_data(UserData::HandshakeTimeout(client_id));
+    [poll, timeout]
+}
+
+/// Cancel a client's `FillPipe`.  Its completion comes back as an error.
+pub fn cancel_fill(client_id: ClientId) -> squeue::Entry {
+    opcode::AsyncCancel::new(UserData::FillPipe(client_id).encode())
+        .build()
+        .user_data(UserData::Cancel)
+}
+
+/// Stop accepting new connections, and abandon any half-finished handshakes
+pub fn stop_accepting(sqes: &mut Vec<squeue::Entry>, acceptor: &mut Acceptor) {
+    acceptor.stopped = true;
+    if acceptor.accept_in_flight {
+        let sqe = opcode::AsyncCancel::new(UserData::Accept.encode())
+            .build()
+            .user_data(UserData::Cancel);
+        sqes.push(sqe);
+    }
+    for (_, handshake) in acceptor.handshakes.drain() {
+        let _ = handshake.conn.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Issue IOs for new connections
+///
+/// ## Handshakes
+///
+/// New connections are accepted by a multishot `Accept`.  Each one then has
+/// to complete the websocket handshake before it becomes a `Client`.  The
+/// handshake is a small state machine (see [`Handshake`]): we poll the socket,
+/// and whenever it's ready we read or write as much as we can without blocking.
+/// Each poll is linked to a timeout, and the handshake as a whole has a
+/// deadline, so slow clients can't hang around forever.
+pub fn accept_clients(sqes: &mut Vec<squeue::Entry>, acceptor: &mut Acceptor) {
+    let backing_off = acceptor.backoff_until.is_some_and(|x| Instant::now() < x);
+    if !acceptor.accept_in_flight && !backing_off && !acceptor.stopped {
+        sqes.push(accept(&acceptor.listener));
+        acceptor.accept_in_flight = true;
+        acceptor.backoff_until = None;
+    }
+    for (client_id, handshake) in &mut acceptor.handshakes {
+        if !handshake.poll_in_flight {
+            sqes.extend(poll_handshake(*client_id, handshake));
+            handshake.poll_in_flight = true;
+        }
+    }
+}
+
 /// Issue IOs for a single client
 ///
 /// ## Why fill and drain a pipe?
@@ -84,21 +372,133 @@ fn drain_pipe(client_id: ClientId, client: &mut Client) -> squeue::Entry {
 /// and the bytes will remain in the pipe.  `send_in_flight` will be stuck at
 /// `true`, preventing any more `DrainPipe`s from being submitted.
 ///
-/// We'll keep submitting `FillPipe`s until the pipe's buffer fills up. At this
-/// point, the next `FillPipe` will block.  `copy_in_flight` be stuck at `true`,
-/// preventing any more `FillPipe`s from being submitted.
+/// We'll keep submitting `FillPipe`s until the pipe's buffer fills up, each one
+/// no bigger than the room left in the pipe.  Once it's full we stop, rather
+/// than submitting a `FillPipe` which would block.  (The pipe's capacity is
+/// really counted in pages, so a `FillPipe` can still block sometimes, and
+/// then `copy_in_flight` is stuck at `true` until the pipe drains.)
+///
+/// ## Filtered clients
+///
+/// Clients with `wantedCollections` are fed from several files.  We send them
+/// a run of frames from one file at a time; see
+/// [`crate::collections::Interleaver::next_run`].  Clients with `compress` or
+/// `fsst` are fed the same way, but from just one file.
+///
+/// Clients with `wantedDids` are fed from `jetrelay.dat` like everyone else,
+/// but we look up their frames in the DID index and send them one at a time.
+///
+/// Clients with `maxMessageSizeBytes` have their ranges cut short at the next
+/// frame which is too big.  That frame is then skipped.
+///
+/// ## Receiving
+///
+/// We always have a `PollConn` in flight for each client.  When it completes,
+/// we read whatever the client sent us; see [`crate::control`].  If the client
+/// has sent new options, we stop at the next frame boundary and switch to a
+/// new feed.  Likewise, if we have control frames to send, we stop at the next
+/// frame boundary, wait for the pipe to drain, and send them directly.
 pub fn get_client_caught_up(
     sqes: &mut Vec<squeue::Entry>,
     file_len: u64,
+    policy: &Policy,
     client_id: ClientId,
     client: &mut Client,
 ) -> Result<()> {
     let _g = debug_span!("", client_id).entered();
-    if !client.copy_in_flight && client.offset < file_len {
-        let n_bytes = u32::try_from(file_len - client.offset).unwrap();
-        debug!("Copying {n_bytes} bytes into the pipe");
-        sqes.push(fill_pipe(client_id, client, n_bytes));
-        client.copy_in_flight = true;
+    if !client.poll_in_flight {
+        sqes.push(poll_conn(client_id, client));
+        client.poll_in_flight = true;
+    }
+    crate::control::keep_alive(client, policy.keepalive_timeout);
+    crate::control::check_close_deadline(client);
+    // New options can move the client back into data which has been dropped,
+    // so they have to be applied before we check for that
+    client.apply_pending_options(file_len)?;
+    let dropped = crate::policy::check_dropped_data(sqes, client_id, client, policy.dropped_data);
+    crate::policy::check_slow_consumer(client, policy.slow_consumer, file_len);
+
+    let at_boundary = client.at_frame_boundary(file_len);
+    if !client.outbox.is_empty()
+        && at_boundary
+        && !client.send_in_flight
+        && client.bytes_in_pipe == 0
+    {
+        crate::control::flush_outbox(client);
+    }
+    // If we've got something to do at the next frame boundary, then we stop
+    // there until it's done
+    let stop_at_boundary = client.closing
+        || !client.outbox.is_empty()
+        || client.pending_options.is_some()
+        || client.skip_to_live
+        || client.frozen;
+    let paused = stop_at_boundary && at_boundary;
+    let room = PIPE_CAPACITY.saturating_sub(client.bytes_in_pipe);
+    if !client.copy_in_flight && !paused && !dropped && room > 0 {
+        match &mut client.feed {
+            Feed::Hello(_) => (),
+            Feed::All if client.offset < file_len => {
+                let limit = client.max_message_size;
+                let mut range = sendable_range(&FRAME_SIZES, client.offset, file_len, limit);
+                client.offset = range.start;
+                if stop_at_boundary {
+                    // Stop at the end of this frame
+                    let frame_end = FRAME_SIZES.lock().unwrap().frame_end(range.start);
+                    range.end = range.end.min(frame_end.unwrap_or(range.end));
+                }
+                if !range.is_empty() {
+                    let n_bytes = u32::try_from((range.end - range.start).min(room)).unwrap();
+                    debug!("Copying {n_bytes} bytes into the pipe");
+                    sqes.push(fill_pipe(client_id, client, n_bytes));
+                    client.copy_in_flight = true;
+                }
+            }
+            Feed::All => (),
+            Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+                let run = match interleaver.run {
+                    Some(ref mut run) => Some(run),
+                    None => interleaver.next_run(),
+                };
+                if let Some(run) = run {
+                    let limit = client.max_message_size;
+                    let range = sendable_range(&run.file.sizes, run.offset, run.end, limit);
+                    run.offset = range.start;
+                    if !range.is_empty() {
+                        let n_bytes = u32::try_from((range.end - range.start).min(room)).unwrap();
+                        debug!(
+                            "Copying {n_bytes} bytes from {} into the pipe",
+                            run.file.name
+                        );
+                        sqes.push(fill_pipe_from_run(
+                            client_id,
+                            &client.pipe_wtr,
+                            run,
+                            n_bytes,
+                        ));
+                        client.copy_in_flight = true;
+                    } else if run.offset >= run.end {
+                        interleaver.run = None;
+                    }
+                }
+            }
+            Feed::Dids(dids) => {
+                let range = match dids.run_end {
+                    Some(end) => Some((client.offset, end)),
+                    None => dids.next_frame(client.offset, file_len, client.max_message_size),
+                };
+                if let Some((start, end)) = range {
+                    client.offset = start;
+                    let n_bytes = u32::try_from((end - start).min(room)).unwrap();
+                    debug!("Copying a {n_bytes} byte frame into the pipe");
+                    sqes.push(fill_pipe(client_id, client, n_bytes));
+                    client.copy_in_flight = true;
+                } else {
+                    // Nothing for this client in the rest of the file
+                    client.offset = file_len;
+                }
+            }
+        }
     }
     if !client.send_in_flight && client.bytes_in_pipe > 0 {
         debug!("Sending {} bytes to the socket", client.bytes_in_pipe);
@@ -108,30 +508,102 @@ pub fn get_client_caught_up(
     Ok(())
 }
 
+/// Clip `start..end` so that it doesn't contain any frames which are too big
+/// for the client
+fn sendable_range(sizes: &Mutex<FrameSizes>, start: u64, end: u64, limit: u64) -> Range<u64> {
+    if limit == u64::MAX {
+        start..end // Fast path: no need to look at the sizes
+    } else {
+        sizes.lock().unwrap().sendable_range(start, end, limit)
+    }
+}
+
 pub fn handle_completion(
     clients: &mut HashMap<ClientId, Client>,
+    acceptor: &mut Acceptor,
+    waker: &mut Waker,
+    signals: &mut Signals,
+    file_len: &AtomicU64,
     cqe: cqueue::Entry,
 ) -> Result<()> {
     let user_data = UserData::try_from(cqe.user_data())?;
+    crate::metrics::COMPLETIONS[user_data.kind()].fetch_add(1, Ordering::Relaxed);
     let result = cqe.result();
     debug!("{user_data:?} completed with {result:?}");
     let (client_id, was_fill) = match user_data {
         UserData::Timeout => return Ok(()),
         UserData::FillPipe(client_id) => (client_id, true),
         UserData::DrainPipe(client_id) => (client_id, false),
+        UserData::PollConn(client_id) => return handle_poll(clients, client_id, result),
+        UserData::Accept => {
+            if !cqueue::more(cqe.flags()) {
+                acceptor.accept_in_flight = false;
+            }
+            match result {
+                Ok(fd) => {
+                    // SAFETY: The kernel just gave us this fd
+                    let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+                    if acceptor.stopped {
+                        return Ok(()); // Snuck in before the cancellation
+                    }
+                    acceptor.start_handshake(TcpStream::from(fd), clients.len());
+                }
+                Err(_) if acceptor.stopped => (),
+                Err(e) => {
+                    error!("Couldn't accept a connection: {e}");
+                    acceptor.backoff_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                }
+            }
+            return Ok(());
+        }
+        UserData::PollHandshake(client_id) => {
+            handle_handshake_poll(clients, acceptor, file_len, client_id, result);
+            return Ok(());
+        }
+        UserData::HandshakeTimeout(_) => return Ok(()),
+        UserData::Wakeup => {
+            if !cqueue::more(cqe.flags()) {
+                waker.poll_in_flight = false;
+            }
+            // Reset the counter, and then clear the flag.  The other way round,
+            // a wakeup in between could have its write swallowed, with the flag
+            // left set, and then every later wakeup would be skipped.  This
+            // way, a wakeup in between is skipped, but we haven't looked at the
+            // clients yet, so we'll still see its data.  (The swap syncs with
+            // the one in `wake_runloop` to make sure of that.)
+            let _ = rustix::io::read(EVENTFD.get().unwrap(), &mut [0; 8]);
+            WAKEUP_PENDING.swap(false, Ordering::AcqRel);
+            return Ok(());
+        }
+        UserData::Signal => {
+            if !cqueue::more(cqe.flags()) {
+                signals.poll_in_flight = false;
+            }
+            signals.read();
+            return Ok(());
+        }
+        UserData::Cancel => return Ok(()),
     };
     let _g = info_span!("", client_id).entered();
+    if was_fill && matches!(result, Err(Errno::CANCELED | Errno::INTR)) {
+        debug!("Fill was cancelled");
+        if let Some(client) = clients.get_mut(&client_id) {
+            client.copy_in_flight = false;
+            client.cancel_in_flight = false;
+        }
+        return Ok(());
+    }
     if matches!(result, Err(Errno::PIPE | Errno::CONNRESET | Errno::BADF)) {
         if was_fill {
             // This happens when the client is gone
             assert!(clients.get_mut(&client_id).is_none());
-            return Ok(());
-        } else {
+        } else if clients.remove(&client_id).is_some() {
             info!("Socket closed by other side");
-            let client = clients.remove(&client_id);
-            ensure!(client.is_some(), "Two hangups for the same client?");
-            return Ok(());
+        } else {
+            // We already dropped the client, which shut down the socket
+            debug!("Send failed after the client was removed");
         }
+        return Ok(());
     }
     let Some(client) = clients.get_mut(&client_id) else {
         warn!("Got an IO completion but the client is gone");
@@ -141,15 +613,143 @@ pub fn handle_completion(
     if was_fill {
         ensure!(client.copy_in_flight);
         client.copy_in_flight = false;
+        // Too late, if we'd tried to cancel it
+        client.cancel_in_flight = false;
         ensure!(bytes_written != 0);
         client.bytes_in_pipe += bytes_written;
-        client.offset += bytes_written;
+        crate::metrics::BYTES_FILLED.fetch_add(bytes_written, Ordering::Relaxed);
+        match &mut client.feed {
+            Feed::Hello(_) => bail!("Filled pipe before the client said hello"),
+            Feed::All => {
+                client.offset += bytes_written;
+                // Remember this now, in case the frame is dropped before
+                // we've sent the rest of it
+                let sizes = FRAME_SIZES.lock().unwrap();
+                client.frame_end = sizes.frame_end(client.offset - 1).unwrap_or(client.offset);
+            }
+            Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+                let run = interleaver
+                    .run
+                    .as_mut()
+                    .context("Filled pipe without a run")?;
+                run.offset += bytes_written;
+                let sizes = run.file.sizes.lock().unwrap();
+                run.frame_end = sizes.frame_end(run.offset - 1).unwrap_or(run.offset);
+                drop(sizes);
+                if run.offset >= run.end {
+                    interleaver.run = None;
+                }
+            }
+            Feed::Dids(dids) => {
+                client.offset += bytes_written;
+                if dids.run_end.is_some_and(|end| client.offset >= end) {
+                    dids.run_end = None;
+                }
+            }
+        }
     } else {
         ensure!(client.send_in_flight);
         client.send_in_flight = false;
         ensure!(bytes_written != 0);
         debug!("Sent {bytes_written} bytes to client");
+        crate::metrics::BYTES_DRAINED.fetch_add(bytes_written, Ordering::Relaxed);
         client.bytes_in_pipe -= bytes_written;
+        client.bytes_sent += bytes_written;
+    }
+    Ok(())
+}
+
+fn handle_poll(
+    clients: &mut HashMap<ClientId, Client>,
+    client_id: ClientId,
+    result: Result<u32, Errno>,
+) -> Result<()> {
+    let _g = info_span!("", client_id).entered();
+    let Some(client) = clients.get_mut(&client_id) else {
+        // Dropping the client shuts down the socket, which wakes up the poll
+        return Ok(());
+    };
+    client.poll_in_flight = false;
+    if let Err(e) = result {
+        warn!("Couldn't poll the socket: {e}");
+        clients.remove(&client_id);
+        return Ok(());
+    }
+    match crate::control::read_messages(client) {
+        Ok(true) => (),
+        Ok(false) => {
+            info!("Client hung up");
+            clients.remove(&client_id);
+        }
+        Err(e) => {
+            warn!("Disconnecting client: {e:#}");
+            clients.remove(&client_id);
+        }
     }
     Ok(())
 }
+
+fn handle_handshake_poll(
+    clients: &mut HashMap<ClientId, Client>,
+    acceptor: &mut Acceptor,
+    file_len: &AtomicU64,
+    client_id: ClientId,
+    result: Result<u32, Errno>,
+) {
+    let _g = info_span!("", client_id).entered();
+    // Not counting this one
+    let n_clients = clients.len() + acceptor.n_pending().saturating_sub(1);
+    let Some(handshake) = acceptor.handshakes.get_mut(&client_id) else {
+        return;
+    };
+    let limits = &mut acceptor.limits;
+    handshake.poll_in_flight = false;
+    let done = match result {
+        Err(Errno::CANCELED) => Err((HandshakeOutcome::Timeout, anyhow!("Handshake timed out"))),
+        Err(e) => Err((HandshakeOutcome::Io, e.into())),
+        Ok(_) if Instant::now() > handshake.deadline => {
+            Err((HandshakeOutcome::Timeout, anyhow!("Handshake timed out")))
+        }
+        Ok(_) => handshake.advance(limits, n_clients).map_err(|e| {
+            let outcome = if e.is::<Errno>() {
+                HandshakeOutcome::Io
+            } else if e.is::<Rejection>() {
+                HandshakeOutcome::Limited
+            } else {
+                HandshakeOutcome::BadRequest
+            };
+            (outcome, e)
+        }),
+    };
+    match done {
+        Ok(false) => (), // We'll poll again
+        Ok(true) => {
+            let handshake = acceptor.handshakes.remove(&client_id).unwrap();
+            let (conn, peer_addr, config, recv_buf, permit) = handshake.finish();
+            match Client::new(conn, peer_addr, config, recv_buf, file_len, permit) {
+                Ok(client) => {
+                    clients.insert(client_id, client);
+                    crate::metrics::count_handshake(HandshakeOutcome::Success);
+                    info!("Client registered");
+                }
+                Err(e) => {
+                    crate::metrics::count_handshake(HandshakeOutcome::Rejected);
+                    error!("{e}");
+                }
+            }
+        }
+        Err((HandshakeOutcome::Limited, e)) => {
+            crate::metrics::count_handshake(HandshakeOutcome::Limited);
+            // Not a warning, since there could be a lot of these
+            info!("Turned the client away: {e}");
+            let handshake = acceptor.handshakes.remove(&client_id).unwrap();
+            let _ = handshake.conn.shutdown(std::net::Shutdown::Both);
+        }
+        Err((outcome, e)) => {
+            crate::metrics::count_handshake(outcome);
+            warn!("Handshake failed: {e:#}");
+            let handshake = acceptor.handshakes.remove(&client_id).unwrap();
+            let _ = handshake.conn.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
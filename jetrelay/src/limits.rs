@@ -0,0 +1,334 @@
+//! Connection limits
+//!
+//! So that no one source can hog the relay, or flood it with connections, we
+//! can limit:
+//!
+//! * the total number of clients (`MAX_CLIENTS`);
+//! * the number of clients connected from each IP (`MAX_CLIENTS_PER_IP`), or
+//!   from particular ranges of IPs (`CIDR_CLIENT_LIMITS`); and
+//! * how often each IP can connect (`NEW_CLIENTS_PER_SEC_PER_IP`).
+//!
+//! IPv6 addresses are grouped by /64, since that's usually what one host gets.
+//!
+//! The limits are checked as soon as we accept a connection, and connections
+//! which are part-way through the handshake count towards them, so that
+//! clients can't get round them by holding handshakes open.  (Connections
+//! through a trusted proxy are the exception: we don't know who they're from
+//! until we've read the PROXY header.)  We still wait for the client's
+//! handshake request before turning it away, so that we can send a proper HTTP
+//! response (429 or 503, with `Retry-After`) rather than just hanging up.
+//!
+//! Separately, there's a cap on the number of handshakes in progress at once.
+//! Past that, new connections are hung up on straight away.
+
+use anyhow::{Context, Result, ensure};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What we tell clients when we don't know when there'll be room for them
+const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// How often to forget about IPs which haven't connected in a while
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many clients are connected from each address (or /64), and from each
+/// of the ranges in `CIDR_CLIENT_LIMITS`
+static CONNECTED: Mutex<BTreeMap<Key, usize>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Key {
+    Ip(IpAddr),
+    /// An index into `Limits::cidrs`
+    Cidr(usize),
+}
+
+#[derive(Debug)]
+pub struct Limits {
+    max_clients: Option<usize>,
+    max_clients_per_ip: Option<usize>,
+    /// Clients in each range share the limit
+    cidrs: Vec<(Cidr, usize)>,
+    /// Bursts of up to a second's worth are allowed
+    new_clients_per_sec: Option<f64>,
+    buckets: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+impl Limits {
+    pub fn new(
+        max_clients: Option<usize>,
+        max_clients_per_ip: Option<usize>,
+        cidrs: Vec<(Cidr, usize)>,
+        new_clients_per_sec: Option<f64>,
+    ) -> Limits {
+        Limits {
+            max_clients,
+            max_clients_per_ip,
+            cidrs,
+            new_clients_per_sec,
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Decide whether to let a client in.  `n_clients` is the number which are
+    /// already connected.
+    pub fn admit(&mut self, ip: Option<IpAddr>, n_clients: usize) -> Result<Permit, Rejection> {
+        if self.max_clients.is_some_and(|max| n_clients >= max) {
+            return Err(Rejection::ServerFull);
+        }
+        let Some(ip) = ip else {
+            return Ok(Permit(vec![]));
+        };
+        if let Some(rate) = self.new_clients_per_sec {
+            self.prune(rate);
+            let bucket = self.buckets.entry(group(ip)).or_insert(Bucket {
+                tokens: burst(rate),
+                updated: Instant::now(),
+            });
+            if let Err(retry_after) = bucket.take(rate) {
+                return Err(Rejection::TooOften { retry_after });
+            }
+        }
+        let keys = self.keys(ip);
+        let connected = CONNECTED.lock().unwrap();
+        for key in &keys {
+            let limit = match *key {
+                Key::Ip(_) => self.max_clients_per_ip,
+                Key::Cidr(i) => Some(self.cidrs[i].1),
+            };
+            let n = connected.get(key).copied().unwrap_or(0);
+            if limit.is_some_and(|limit| n >= limit) {
+                return Err(Rejection::TooManyClients);
+            }
+        }
+        drop(connected);
+        Ok(Permit::new(keys))
+    }
+
+    /// Count a client towards the limits without checking them
+    pub fn permit(&self, ip: Option<IpAddr>) -> Permit {
+        match ip {
+            Some(ip) => Permit::new(self.keys(ip)),
+            None => Permit(vec![]),
+        }
+    }
+
+    fn keys(&self, ip: IpAddr) -> Vec<Key> {
+        let ip = ip.to_canonical();
+        let mut keys = vec![];
+        if self.max_clients_per_ip.is_some() {
+            keys.push(Key::Ip(group(ip)));
+        }
+        for (i, (cidr, _)) in self.cidrs.iter().enumerate() {
+            if cidr.contains(ip) {
+                keys.push(Key::Cidr(i));
+            }
+        }
+        keys
+    }
+
+    /// Forget about buckets which have filled up again
+    fn prune(&mut self, rate: f64) {
+        let now = Instant::now();
+        if now - self.last_prune < PRUNE_INTERVAL {
+            return;
+        }
+        let refill_time = Duration::from_secs_f64(burst(rate) / rate);
+        self.buckets.retain(|_, x| now - x.updated < refill_time);
+        self.last_prune = now;
+    }
+}
+
+/// IPv6 addresses are grouped by /64
+fn group(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(x) => IpAddr::V4(x),
+        IpAddr::V6(x) => IpAddr::V6(Ipv6Addr::from_bits(x.to_bits() & !(u64::MAX as u128))),
+    }
+}
+
+fn burst(rate: f64) -> f64 {
+    rate.max(1.0)
+}
+
+/// A token bucket
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Returns how long until there'll be a token, if there isn't one now
+    fn take(&mut self, rate: f64) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = (now - self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst(rate));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// Counts a client towards the limits, for as long as it's alive
+#[derive(Debug)]
+pub struct Permit(Vec<Key>);
+
+impl Permit {
+    fn new(keys: Vec<Key>) -> Permit {
+        let mut connected = CONNECTED.lock().unwrap();
+        for key in &keys {
+            *connected.entry(*key).or_default() += 1;
+        }
+        Permit(keys)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut connected = CONNECTED.lock().unwrap();
+        for key in &self.0 {
+            if let Some(n) = connected.get_mut(key) {
+                *n -= 1;
+                if *n == 0 {
+                    connected.remove(key);
+                }
+            }
+        }
+    }
+}
+
+/// Why we turned a client away
+#[derive(Debug, Clone, Copy)]
+pub enum Rejection {
+    /// `MAX_CLIENTS`
+    ServerFull,
+    /// `MAX_CLIENTS_PER_IP` or `CIDR_CLIENT_LIMITS`
+    TooManyClients,
+    /// `NEW_CLIENTS_PER_SEC_PER_IP`
+    TooOften { retry_after: Duration },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rejection::ServerFull => "The server is full",
+            Rejection::TooManyClients => "Too many connections from your address",
+            Rejection::TooOften { .. } => "Too many new connections from your address",
+        })
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+impl Rejection {
+    /// What we send the client instead of the websocket handshake response
+    pub fn response(&self) -> Vec<u8> {
+        let (status, retry_after) = match *self {
+            Rejection::ServerFull => ("503 Service Unavailable", RETRY_AFTER),
+            Rejection::TooManyClients => ("429 Too Many Requests", RETRY_AFTER),
+            Rejection::TooOften { retry_after } => ("429 Too Many Requests", retry_after),
+        };
+        let body = format!("{self}\n");
+        let mut buf = vec![];
+        writeln!(buf, "HTTP/1.1 {status}\r").unwrap();
+        writeln!(
+            buf,
+            "Retry-After: {}\r",
+            retry_after.as_secs_f64().ceil().max(1.0) as u64
+        )
+        .unwrap();
+        writeln!(buf, "Content-Type: text/plain\r").unwrap();
+        writeln!(buf, "Content-Length: {}\r", body.len()).unwrap();
+        writeln!(buf, "Connection: close\r").unwrap();
+        writeln!(buf, "\r").unwrap();
+        buf.extend_from_slice(body.as_bytes());
+        buf
+    }
+}
+
+/// A range of IP addresses, like `192.0.2.0/24`.  A bare address is a range
+/// of one.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                a.to_bits() & mask == b.to_bits() & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                a.to_bits() & mask == b.to_bits() & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("Bad address: {s:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => max,
+            x => x
+                .parse()
+                .with_context(|| format!("Bad prefix length: {s:?}"))?,
+        };
+        ensure!(prefix_len <= max, "Bad prefix length: {s:?}");
+        Ok(Cidr {
+            addr: addr.to_canonical(),
+            prefix_len,
+        })
+    }
+}
+
+/// Parses `CIDR_CLIENT_LIMITS`, eg. "192.0.2.0/24=100,2001:db8::/32=50"
+pub fn parse_cidr_limits(s: &str) -> Result<Vec<(Cidr, usize)>> {
+    let mut out = vec![];
+    for x in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let (cidr, limit) = x.split_once('=').context("Expected <cidr>=<limit>")?;
+        out.push((cidr.parse()?, limit.parse()?));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidrs() {
+        let cidr: Cidr = "192.0.2.0/24".parse().unwrap();
+        assert!(cidr.contains("192.0.2.77".parse().unwrap()));
+        assert!(cidr.contains("::ffff:192.0.2.77".parse().unwrap()));
+        assert!(!cidr.contains("192.0.3.1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db8::1".parse().unwrap()));
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+        let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains("203.0.113.5".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<Cidr>().is_err());
+    }
+}
@@ -1,29 +1,82 @@
+mod admin;
+mod collections;
+mod compression;
+mod control;
+mod dashboard;
+mod datafile;
+mod dids;
 mod handshake;
+mod http;
 mod io;
+mod limits;
+mod merge;
+mod metrics;
+mod policy;
+mod proxy;
+mod shutdown;
+mod systemd;
+mod upgrade;
 mod upstream;
 
-use anyhow::{Context, Result};
+use crate::collections::{CollectionFilter, Interleaver};
+use crate::dids::DidFilter;
+use crate::handshake::{Acceptor, ClientConfig, Compression, Options};
+use crate::limits::{Cidr, Limits, Permit};
+use crate::policy::{DroppedDataPolicy, Policy, SlowConsumer, SlowConsumerAction};
+use crate::upstream::{DROP_GENERATION, Timestamp};
+use anyhow::{Context, Result, bail, ensure};
 use rustix::fd::{AsRawFd, OwnedFd};
 use rustix_uring::IoUring;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
 use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 use tracing::*;
-use tracing_subscriber::{EnvFilter, prelude::*};
+use tracing_subscriber::{EnvFilter, prelude::*, reload};
+
+/// Clients which don't respond to pings for this long are disconnected
+const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+/// If upstream sends nothing for this long, we reconnect
+const DEFAULT_UPSTREAM_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// If upstream's events fall this far behind the wall clock, we reconnect
+const DEFAULT_UPSTREAM_MAX_LAG: Duration = Duration::from_secs(30);
+/// Once we have this much data, we drop the older half of it
+const DEFAULT_RETENTION: Duration = Duration::from_secs(2 * 60);
 
 /// Respects the following env vars:
 ///
-/// * JETRELAY_PORT (required)
-/// * UPSTREAM_URL (required)
+/// * JETRELAY_PORT (required, unless systemd passes us a socket)
+/// * UPSTREAM_URL (required; may be a comma-separated list)
+/// * UPSTREAM_MODE
+/// * UPSTREAM_STALL_TIMEOUT
+/// * UPSTREAM_MAX_LAG
 /// * RUNTIME_DIRECTORY (required)
+/// * RETENTION
+/// * RETENTION_BYTES
+/// * ZSTD_DICTIONARY
+/// * ENABLE_FSST
+/// * KEEPALIVE_TIMEOUT
+/// * DROPPED_DATA_POLICY
+/// * SLOW_CONSUMER_POLICY
+/// * SLOW_CONSUMER_MAX_LAG
+/// * SLOW_CONSUMER_MAX_LAG_BYTES
+/// * METRICS_PORT
+/// * SHUTDOWN_GRACE_PERIOD
+/// * MAX_CLIENTS
+/// * MAX_CLIENTS_PER_IP
+/// * CIDR_CLIENT_LIMITS
+/// * NEW_CLIENTS_PER_SEC_PER_IP
+/// * TRUSTED_PROXIES
 /// * RUST_LOG
 fn main() -> Result<()> {
     log_init();
+    // Before we start any threads, so that they don't get the signals either
+    let mut signals = crate::io::Signals::new()?;
 
     // Set up the uring
     let mut uring = IoUring::new(1024)?;
@@ -33,57 +86,305 @@ fn main() -> Result<()> {
 
     let var = "RUNTIME_DIRECTORY";
     let dir: PathBuf = std::env::var(var).context(var)?.into();
-    let file = create_file(&dir, &uring)?;
+    let file = open_file(&dir, &uring)?;
+    let collections = crate::collections::Writer::new(dir.clone())?;
+    let zstd = match std::env::var_os("ZSTD_DICTIONARY") {
+        Some(path) => {
+            let dictionary = std::fs::read(&path).context("ZSTD_DICTIONARY")?;
+            Some(crate::compression::ZstdWriter::new(&dir, &dictionary)?)
+        }
+        None => {
+            info!("ZSTD_DICTIONARY is not set; compression is disabled");
+            None
+        }
+    };
+    let var = "ENABLE_FSST";
+    let fsst = match std::env::var(var).as_deref() {
+        Ok("true" | "1") => Some(crate::compression::FsstWriter::new(&dir)?),
+        Ok("false" | "0") | Err(_) => None,
+        Ok(x) => bail!("{var}: Expected \"true\" or \"false\", saw {x:?}"),
+    };
     let file_len = Arc::new(AtomicU64::new(0));
 
+    let var = "RETENTION";
+    let max_age = match std::env::var(var) {
+        Ok(x) => Duration::from_secs(x.parse().context(var)?),
+        Err(_) => DEFAULT_RETENTION,
+    };
+    let var = "RETENTION_BYTES";
+    let max_bytes = match std::env::var(var) {
+        Ok(x) => Some(x.parse().context(var)?),
+        Err(_) => None,
+    };
+    let mut sink = crate::upstream::Sink {
+        file,
+        file_len: file_len.clone(),
+        collections,
+        zstd,
+        fsst,
+        retention: crate::upstream::Retention::new(max_age, max_bytes),
+    };
+
+    let var = "KEEPALIVE_TIMEOUT";
+    let keepalive_timeout = match std::env::var(var) {
+        Ok(x) => Duration::from_secs(x.parse().context(var)?),
+        Err(_) => DEFAULT_KEEPALIVE_TIMEOUT,
+    };
+    let var = "DROPPED_DATA_POLICY";
+    let dropped_data = match std::env::var(var) {
+        Ok(x) => x.parse().context(var)?,
+        Err(_) => DroppedDataPolicy::Close,
+    };
+    let var = "SLOW_CONSUMER_POLICY";
+    let action = match std::env::var(var) {
+        Ok(x) => x.parse().context(var)?,
+        Err(_) => SlowConsumerAction::Keep,
+    };
+    let var = "SLOW_CONSUMER_MAX_LAG";
+    let max_lag = match std::env::var(var) {
+        Ok(x) => Some(Duration::from_secs(x.parse().context(var)?)),
+        Err(_) => None,
+    };
+    let var = "SLOW_CONSUMER_MAX_LAG_BYTES";
+    let max_lag_bytes = match std::env::var(var) {
+        Ok(x) => Some(x.parse().context(var)?),
+        Err(_) => None,
+    };
+    let policy = Policy {
+        keepalive_timeout,
+        dropped_data,
+        slow_consumer: SlowConsumer {
+            action: Some(action),
+            max_lag,
+            max_lag_bytes,
+        },
+    };
+
+    // If we're taking over from an older jetrelay, it gives us its sockets
+    let takeover = crate::upgrade::Takeover::receive()?;
+
     // Bind the listener socket.  We do this ASAP, so clients can start
     // connecting immediately. It's fine for them to connect even before the
     // file exists.  Of course, they won't recieve any data until it _does_
     // exist.
-    let var = "JETRELAY_PORT";
-    let port: u16 = std::env::var(var).context(var)?.parse().context(var)?;
-    let listen_addr = SocketAddr::new([0, 0, 0, 0].into(), port);
-    let listener = TcpListener::bind(listen_addr)?;
-    info!(%listen_addr, "Bound socket");
-
-    // Handle incoming client connections in a separate thread
-    let (client_tx, client_rx) = std::sync::mpsc::channel();
-    let file_len_2 = file_len.clone();
-    std::thread::Builder::new()
-        .name("client_listener".to_owned())
-        .spawn(move || listen_for_clients(listener, client_tx, file_len_2))?;
+    let systemd_listener = match &takeover {
+        Some(_) => None,
+        None => crate::systemd::listen_fds()?,
+    };
+    let listener = match (&takeover, systemd_listener) {
+        (Some(takeover), _) => takeover.listener.try_clone()?,
+        (None, Some(x)) => x,
+        (None, None) => {
+            let var = "JETRELAY_PORT";
+            let port: u16 = std::env::var(var).context(var)?.parse().context(var)?;
+            let listen_addr = SocketAddr::new([0, 0, 0, 0].into(), port);
+            let listener = TcpListener::bind(listen_addr)?;
+            info!(%listen_addr, "Bound socket");
+            listener
+        }
+    };
+
+    let var = "SHUTDOWN_GRACE_PERIOD";
+    let grace_period = match std::env::var(var) {
+        Ok(x) => Duration::from_secs(x.parse().context(var)?),
+        Err(_) => crate::shutdown::DEFAULT_GRACE_PERIOD,
+    };
+
+    let var = "MAX_CLIENTS";
+    let max_clients = match std::env::var(var) {
+        Ok(x) => Some(x.parse().context(var)?),
+        Err(_) => None,
+    };
+    let var = "MAX_CLIENTS_PER_IP";
+    let max_clients_per_ip = match std::env::var(var) {
+        Ok(x) => Some(x.parse().context(var)?),
+        Err(_) => None,
+    };
+    let var = "CIDR_CLIENT_LIMITS";
+    let cidrs = match std::env::var(var) {
+        Ok(x) => crate::limits::parse_cidr_limits(&x).context(var)?,
+        Err(_) => vec![],
+    };
+    let var = "NEW_CLIENTS_PER_SEC_PER_IP";
+    let new_clients_per_sec = match std::env::var(var) {
+        Ok(x) => {
+            let rate: f64 = x.parse().context(var)?;
+            ensure!(rate > 0.0, "{var}: Must be positive");
+            Some(rate)
+        }
+        Err(_) => None,
+    };
+    let limits = Limits::new(max_clients, max_clients_per_ip, cidrs, new_clients_per_sec);
+    let var = "TRUSTED_PROXIES";
+    let trusted_proxies = match std::env::var(var) {
+        Ok(x) => x
+            .split(',')
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.parse())
+            .collect::<Result<Vec<Cidr>>>()
+            .context(var)?,
+        Err(_) => vec![],
+    };
 
+    let mut acceptor = Acceptor::new(listener, limits, trusted_proxies);
+    let mut waker = crate::io::Waker::new()?;
     let mut clients = HashMap::<ClientId, Client>::default();
-    let mut next_client_id = 0;
 
     let var = "UPSTREAM_URL";
-    let url = std::env::var(var).context(var)?.parse().context(var)?;
-    let ws_iter = wsclient::connect_websocket(&url)?;
-    info!("Connected to upstream");
-    let file_len_2 = file_len.clone();
-    std::thread::Builder::new()
-        .name("upstream_copier".to_owned())
-        .spawn(move || crate::upstream::copy_frames_to_file(file, file_len_2, ws_iter).unwrap())?;
+    let mut urls = vec![];
+    for x in std::env::var(var).context(var)?.split(',') {
+        let url: url::Url = x.trim().parse().context(var)?;
+        // Check this now, rather than when the copier thread tries to connect
+        ensure!(
+            matches!(url.scheme(), "ws" | "wss") && url.host().is_some(),
+            "{var}: Expected a ws:// or wss:// URL"
+        );
+        urls.push(url);
+    }
+    let var = "UPSTREAM_MODE";
+    let merge = match std::env::var(var).as_deref() {
+        Ok("failover") | Err(_) => false,
+        Ok("merge") => true,
+        Ok(x) => bail!("{var}: Expected \"failover\" or \"merge\", saw {x:?}"),
+    };
+    let var = "UPSTREAM_STALL_TIMEOUT";
+    let stall_timeout = match std::env::var(var) {
+        Ok(x) => Duration::from_secs(x.parse().context(var)?),
+        Err(_) => DEFAULT_UPSTREAM_STALL_TIMEOUT,
+    };
+    let var = "UPSTREAM_MAX_LAG";
+    let max_lag = match std::env::var(var) {
+        Ok(x) => Duration::from_secs(x.parse().context(var)?),
+        Err(_) => DEFAULT_UPSTREAM_MAX_LAG,
+    };
+    let watchdog = crate::upstream::Watchdog {
+        stall_timeout,
+        max_lag,
+    };
+
+    let var = "METRICS_PORT";
+    let inherited = takeover.as_ref().and_then(|x| x.metrics_listener.as_ref());
+    let mut metrics_listener = None;
+    if let Ok(port) = std::env::var(var) {
+        let port: u16 = port.parse().context(var)?;
+        let addr = SocketAddr::new([0, 0, 0, 0].into(), port);
+        let listener = match inherited {
+            Some(x) => x.try_clone()?,
+            None => TcpListener::bind(addr).context(var)?,
+        };
+        info!(%addr, "Serving metrics and the status page");
+        // In case we hand over to a new process
+        metrics_listener = Some(listener.try_clone()?);
+        let cx = crate::http::Context {
+            file: sink.file.try_clone()?,
+            upstreams: urls.clone(),
+            merge,
+        };
+        std::thread::Builder::new()
+            .name("http".to_owned())
+            .spawn(move || crate::http::serve(listener, cx))?;
+    }
+
+    // Before we load the data, so that the old process isn't kept waiting
+    // while we do.  It's stopped writing, so the files won't change under us.
+    if let Some(takeover) = takeover {
+        takeover.finish(&mut clients, &mut acceptor)?;
+    }
+    // Bound after the takeover, in case it fails and the old process carries
+    // on
+    let admin = crate::admin::listen(&dir)?;
+
+    // This can take a while, so we do it after binding.  Clients which connect
+    // in the meantime wait in the listen backlog.
+    crate::upstream::load_existing_data(&mut sink)?;
+
+    // So we can fsync it on the way out
+    let data_file = sink.file.try_clone()?;
+    let copier = std::thread::Builder::new().name("upstream_copier".to_owned());
+    if merge {
+        let have = crate::upstream::cursor_overlap(&sink);
+        let frames = crate::merge::merged_frames(urls, watchdog, have)?;
+        copier.spawn(move || crate::upstream::merge_frames_to_file(sink, frames))?;
+    } else {
+        copier.spawn(move || crate::upstream::copy_frames_to_file(sink, urls, watchdog))?;
+    }
 
     let mut sqes = Vec::new();
+    let mut last_snapshot = Instant::now();
+    let mut notifier = crate::systemd::Notifier::new()?;
+    let mut shutdown: Option<crate::shutdown::Shutdown> = None;
+    let mut upgrade: Option<crate::upgrade::Upgrade> = None;
 
     info!("Starting runloop");
     loop {
-        while let Ok(client) = client_rx.try_recv() {
-            let client_id = next_client_id;
-            next_client_id += 1;
-            let _g = info_span!("", client_id).entered();
-            clients.insert(client_id, client);
-            info!("Client registered");
-        }
         for cqe in uring.completion() {
-            crate::io::handle_completion(&mut clients, cqe).context("handle_completion")?;
+            crate::io::handle_completion(
+                &mut clients,
+                &mut acceptor,
+                &mut waker,
+                &mut signals,
+                &file_len,
+                cqe,
+            )
+            .context("handle_completion")?;
         }
+        for signo in signals.received.drain(..) {
+            info!("Received signal {signo}");
+            if signo == libc::SIGUSR2 as u32 {
+                if shutdown.is_none() && upgrade.is_none() {
+                    upgrade = Some(crate::upgrade::Upgrade::start(&mut clients));
+                    crate::io::stop_accepting(&mut sqes, &mut acceptor);
+                }
+                continue;
+            }
+            match &mut shutdown {
+                Some(x) => x.hurry(),
+                None => {
+                    if upgrade.take().is_some() {
+                        crate::upgrade::cancel(&mut clients, &mut acceptor);
+                    }
+                    shutdown = Some(crate::shutdown::Shutdown::start(grace_period));
+                    crate::io::stop_accepting(&mut sqes, &mut acceptor);
+                }
+            }
+        }
+        crate::admin::handle_commands(&admin, &mut clients);
+        waker.arm(&mut sqes);
+        signals.arm(&mut sqes);
+        crate::io::accept_clients(&mut sqes, &mut acceptor);
         let file_len = file_len.load(Ordering::Acquire);
+        if let Some(shutdown) = &mut shutdown
+            && shutdown.advance(&mut clients, file_len)
+        {
+            break;
+        }
+        if let Some(x) = &upgrade
+            && x.ready(&mut clients, file_len)
+        {
+            let x = upgrade.take().unwrap();
+            match x.hand_over(&mut clients, &acceptor, metrics_listener.as_ref(), file_len) {
+                Ok(()) => break,
+                Err(e) => {
+                    error!("Upgrade failed: {e:#}");
+                    crate::upgrade::cancel(&mut clients, &mut acceptor);
+                }
+            }
+        }
+        // Before we look at the clients, so that we see this generation's
+        // low-water marks
+        let drop_generation = DROP_GENERATION.load(Ordering::Acquire);
         for (client_id, client) in &mut clients {
-            crate::io::get_client_caught_up(&mut sqes, file_len, *client_id, client)
+            crate::io::get_client_caught_up(&mut sqes, file_len, &policy, *client_id, client)
                 .context("get_client_caught_up")?;
         }
+        crate::policy::allow_punching(drop_generation, &clients);
+        crate::metrics::record_clients(&clients);
+        notifier.tick(clients.len());
+        if last_snapshot.elapsed() >= crate::dashboard::SNAPSHOT_INTERVAL {
+            crate::dashboard::record_clients(&clients);
+            last_snapshot = Instant::now();
+        }
         sqes.push(crate::io::timeout());
         unsafe {
             uring.submit_all(sqes.drain(..)).context("submit_all")?;
@@ -91,27 +392,12 @@ fn main() -> Result<()> {
         trace!("(Waiting for completions...)");
         uring.submit_and_wait(1).context("submit_and_wait")?;
     }
-}
 
-fn listen_for_clients(listener: TcpListener, client_tx: Sender<Client>, file_len: Arc<AtomicU64>) {
-    std::thread::scope(|scope| {
-        let _g = info_span!("client listener thread").entered();
-        info!(socket = ?listener, "Listening for client connections");
-        for conn in listener.incoming() {
-            std::thread::Builder::new()
-                .name("client_handshake".to_owned())
-                .spawn_scoped(scope, || {
-                    let _g = debug_span!("handshake thread").entered();
-                    match init_client(&client_tx, conn, &file_len) {
-                        Ok(()) => (),
-                        Err(e) => error!("{e}"),
-                    }
-                })
-                .unwrap();
-        }
-        error!("Listening socket was closed!");
-        std::process::exit(1);
-    });
+    // Anything the copier was half-way through writing is truncated at
+    // startup, so it doesn't matter if it's still going
+    data_file.sync_all().context("Couldn't sync jetrelay.dat")?;
+    info!("Synced jetrelay.dat; exiting");
+    Ok(())
 }
 
 type ClientId = u32;
@@ -119,61 +405,286 @@ type ClientId = u32;
 #[derive(Debug)]
 struct Client {
     conn: TcpStream,
+    peer_addr: Option<SocketAddr>,
     offset: u64,
+    /// The end of the last frame we put (some of) in the pipe.  If this is
+    /// past `offset`, we're in the middle of a frame.  Only used by
+    /// `Feed::All`.
+    frame_end: u64,
+    feed: Feed,
+    compression: Compression,
+    /// The options which `feed` was made from
+    options: Options,
+    /// Frames with payloads bigger than this are skipped
+    max_message_size: u64,
+    /// Options which the client has sent us, but we haven't switched to yet
+    pending_options: Option<Options>,
+    /// Bytes received from the client which don't make up a complete frame
+    recv_buf: Vec<u8>,
+    /// Control frames waiting to be sent.  These have to go out between data
+    /// frames, so they wait until the pipe is empty.
+    outbox: Vec<u8>,
+    /// When we last received a frame from the client
+    last_heard: Instant,
+    /// Whether we've pinged the client since `last_heard`
+    ping_pending: bool,
+    /// The connection is being shut down, so we mustn't send any more frames
+    closing: bool,
+    /// When we give up on sending our close frame; see [`control::close`]
+    close_deadline: Option<Instant>,
+    /// Overrides for the deployment's slow consumer policy
+    slow_consumer: SlowConsumer,
+    last_lag_check: Instant,
+    /// How far behind live the client was when we last checked
+    lag: Duration,
+    /// We're going to skip ahead to live at the next frame boundary
+    skip_to_live: bool,
+    /// We're about to hand the client over to a new process (see
+    /// `crate::upgrade`), so we stop at the next frame boundary
+    frozen: bool,
     bytes_in_pipe: u64,
+    /// Sent to the socket, over the lifetime of the connection
+    bytes_sent: u64,
     copy_in_flight: bool,
+    /// We've asked io_uring to cancel the `FillPipe` which is in flight
+    cancel_in_flight: bool,
     send_in_flight: bool,
+    poll_in_flight: bool,
     pipe_rdr: OwnedFd,
     pipe_wtr: OwnedFd,
+    /// Counts towards the connection limits until the client goes away
+    _permit: Permit,
 }
 
-impl Client {
-    fn new(mut conn: TcpStream, file_len: &AtomicU64) -> Result<Client> {
-        let peer_addr = conn.peer_addr()?;
-        let local_addr = conn.local_addr()?;
-        info!(
-            %peer_addr,
-            %local_addr,
-            "New client connected",
-        );
+/// Which frames a client receives, and where they come from
+#[derive(Debug)]
+enum Feed {
+    /// Nothing yet: the client asked for `requireHello`, and we're waiting for
+    /// their first `options_update`.  We'll start from here when it arrives.
+    Hello(Position),
+    /// Everything in `jetrelay.dat`
+    All,
+    /// Fed from the per-collection files instead of `jetrelay.dat`, so
+    /// `Client::offset` is unused
+    Collections(Interleaver),
+    /// Individual frames from `jetrelay.dat`
+    Dids(DidFilter),
+    /// Everything, but compressed.  Fed from `jetrelay.zstd.dat`, so
+    /// `Client::offset` is unused.
+    Zstd(Interleaver),
+    /// Everything, but FSST-compressed.  Fed from `jetrelay.fsst.dat`, so
+    /// `Client::offset` is unused.
+    Fsst(Interleaver),
+}
+
+/// A point in the stream, from which a feed can be started
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    /// For feeds which come from `jetrelay.dat`
+    offset: u64,
+    /// For feeds which come from other files
+    after: Timestamp,
+}
 
-        let config = crate::handshake::perform_handshake(&mut conn)?;
-        info!(cursor = config.cursor.map(|x| x.0), "Handshake complete");
+impl fmt::Display for Feed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Feed::Hello(_) => f.write_str("waiting for hello"),
+            Feed::All => f.write_str("all"),
+            Feed::Collections(interleaver) => match interleaver.filter() {
+                Some(filter) => write!(f, "collections: {filter}"),
+                None => f.write_str("collections"),
+            },
+            Feed::Dids(dids) => write!(f, "dids: {dids}"),
+            Feed::Zstd(_) => f.write_str("all (zstd)"),
+            Feed::Fsst(_) => f.write_str("all (fsst)"),
+        }
+    }
+}
+
+impl Feed {
+    fn interleaver(&mut self) -> Option<&mut Interleaver> {
+        match self {
+            Feed::Collections(x) | Feed::Zstd(x) | Feed::Fsst(x) => Some(x),
+            Feed::Hello(_) | Feed::All | Feed::Dids(_) => None,
+        }
+    }
+
+    /// `after` is only used by feeds which don't come from `jetrelay.dat`
+    fn new(options: &Options, compression: Compression, after: Timestamp) -> Result<Feed> {
+        let collections = if options.wanted_collections.is_empty() {
+            None
+        } else {
+            Some(CollectionFilter::new(&options.wanted_collections)?)
+        };
+        crate::compression::check(compression, options)?;
+        Ok(if !options.wanted_dids.is_empty() {
+            Feed::Dids(DidFilter::new(&options.wanted_dids, collections))
+        } else if let Some(collections) = collections {
+            Feed::Collections(Interleaver::new(collections, after))
+        } else {
+            match compression {
+                Compression::None => Feed::All,
+                // `check()` made sure these exist
+                Compression::Fsst => {
+                    let file = crate::compression::FSST_FILE.get().unwrap();
+                    Feed::Fsst(Interleaver::fixed(file.clone(), after))
+                }
+                Compression::Zstd => {
+                    let file = crate::compression::ZSTD_FILE.get().unwrap();
+                    Feed::Zstd(Interleaver::fixed(file.clone(), after))
+                }
+            }
+        })
+    }
+}
 
+impl Client {
+    /// `recv_buf` is anything the client sent after the handshake request
+    fn new(
+        conn: TcpStream,
+        peer_addr: Option<SocketAddr>,
+        config: ClientConfig,
+        recv_buf: Vec<u8>,
+        file_len: &AtomicU64,
+        permit: Permit,
+    ) -> Result<Client> {
         let offset = config
             .cursor
             .and_then(crate::upstream::resolve_cursor)
             .unwrap_or(file_len.load(Ordering::Acquire));
         info!("Initial offset: {offset}");
 
-        if !config.wanted_collections.is_empty()
-            || !config.wanted_dids.is_empty()
-            || config.max_message_size_bytes != usize::MAX
-        {
-            warn!("Support for filtering is not implemented");
-        }
-        if config.compress {
-            warn!("Support for compression is not implemented");
-        }
-        if config.require_hello {
-            warn!("Interactive mode is not implemented");
-        }
+        let after = match config.cursor {
+            Some(ts) => ts - Duration::from_micros(1),
+            None => Timestamp(crate::datafile::WATERMARK.load(Ordering::Acquire)),
+        };
+        let position = Position { offset, after };
+        Client::starting_at(conn, peer_addr, config, recv_buf, position, permit)
+    }
+
+    /// Like `new`, but the caller has already worked out where to start from
+    fn starting_at(
+        conn: TcpStream,
+        peer_addr: Option<SocketAddr>,
+        config: ClientConfig,
+        recv_buf: Vec<u8>,
+        Position { offset, after }: Position,
+        permit: Permit,
+    ) -> Result<Client> {
+        let feed = if config.require_hello {
+            info!("Waiting for the client to say hello");
+            Feed::Hello(Position { offset, after })
+        } else {
+            Feed::new(&config.options, config.compression, after)?
+        };
 
         let (pipe_rdr, pipe_wtr) = rustix::pipe::pipe()?;
-        Ok(Client {
+        let mut client = Client {
+            peer_addr,
             conn,
             offset,
+            frame_end: offset,
+            feed,
+            compression: config.compression,
+            max_message_size: config.options.max_message_size(),
+            options: config.options,
+            pending_options: None,
+            recv_buf,
+            outbox: vec![],
+            last_heard: Instant::now(),
+            ping_pending: false,
+            closing: false,
+            close_deadline: None,
+            slow_consumer: config.slow_consumer,
+            last_lag_check: Instant::now(),
+            lag: Duration::ZERO,
+            skip_to_live: false,
+            frozen: false,
             bytes_in_pipe: 0,
+            bytes_sent: 0,
             copy_in_flight: false,
+            cancel_in_flight: false,
             send_in_flight: false,
+            poll_in_flight: false,
             pipe_rdr,
             pipe_wtr,
-        })
+            _permit: permit,
+        };
+        // The client may have sent its hello along with the handshake
+        crate::control::handle_messages(&mut client)?;
+        Ok(client)
+    }
+
+    /// Switch to the options in `pending_options`, if any.  We can only do this
+    /// at a frame boundary, so it may take a few goes.
+    fn apply_pending_options(&mut self, file_len: u64) -> Result<()> {
+        if self.pending_options.is_none() {
+            return Ok(());
+        }
+        let Some(position) = self.position(file_len) else {
+            trace!("Not at a frame boundary; will apply the new options later");
+            return Ok(());
+        };
+        let options = self.pending_options.take().unwrap();
+        info!(?options, "Applying new options");
+        self.feed = Feed::new(&options, self.compression, position.after)?;
+        self.offset = position.offset;
+        self.frame_end = position.offset;
+        self.max_message_size = options.max_message_size();
+        self.options = options;
+        Ok(())
+    }
+
+    /// Whether the data we've put in the pipe so far ends on a frame boundary
+    fn at_frame_boundary(&self, file_len: u64) -> bool {
+        if self.copy_in_flight {
+            return false;
+        }
+        match &self.feed {
+            Feed::Hello(_) => true,
+            Feed::All => {
+                let sizes = crate::upstream::FRAME_SIZES.lock().unwrap();
+                self.offset >= file_len || sizes.is_boundary(self.offset)
+            }
+            Feed::Dids(dids) => dids.run_end.is_none(),
+            Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+                interleaver.run.is_none()
+            }
+        }
+    }
+
+    /// How far the client has got.  Returns `None` if we're in the middle of
+    /// sending a frame.
+    fn position(&mut self, file_len: u64) -> Option<Position> {
+        if !self.at_frame_boundary(file_len) {
+            return None;
+        }
+        let offset = self.offset;
+        match &mut self.feed {
+            Feed::Hello(position) => Some(*position),
+            Feed::All | Feed::Dids(_) => Some(Position {
+                offset,
+                after: crate::upstream::timestamp_before(offset),
+            }),
+            Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+                let after = interleaver.position();
+                let offset = crate::upstream::resolve_cursor(after + Duration::from_micros(1));
+                Some(Position {
+                    offset: offset.unwrap_or(file_len),
+                    after,
+                })
+            }
+        }
     }
 }
 
 impl Drop for Client {
     fn drop(&mut self) {
+        if self.closing {
+            // We've already been through the close handshake (or given up)
+            return;
+        }
         trace!("Sending close frame to client");
         let close_frame = [0x88, 0x02, 0x03, 0xE8];
         let _ = self.conn.write_all(&close_frame);
@@ -182,36 +693,30 @@ impl Drop for Client {
     }
 }
 
-fn init_client(
-    client_tx: &Sender<Client>,
-    conn: std::io::Result<TcpStream>,
-    file_len: &AtomicU64,
-) -> Result<()> {
-    let client = Client::new(conn?, file_len)?;
-    client_tx.send(client)?;
-    // We could wake up the io_uring here... but we don't bother
-    Ok(())
-}
-
 /// Respect `RUST_LOG`, falling back to INFO-level
 fn log_init() {
     let filter = EnvFilter::builder()
         .with_default_directive(Level::INFO.into())
         .from_env_lossy();
+    // The admin API can swap the filter out later
+    let (filter, handle) = reload::Layer::new(filter);
+    let _ = crate::admin::LOG_FILTER.set(handle);
     let writer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
     tracing_subscriber::registry()
         .with(filter)
         .with(writer)
+        .with(crate::dashboard::RecentErrors)
         .init();
 }
 
-fn create_file(dir: &Path, uring: &IoUring) -> Result<File> {
+/// Opens `jetrelay.dat`, creating it if this is the first run
+fn open_file(dir: &Path, uring: &IoUring) -> Result<File> {
     let path = dir.join("jetrelay.dat");
-    info!("Creating a file at {}", path.display());
+    info!("Opening {}", path.display());
     let file = File::options()
         .read(true)
         .append(true)
-        .create_new(true)
+        .create(true)
         .open(path)?;
     uring
         .submitter()
@@ -219,3 +724,23 @@ fn create_file(dir: &Path, uring: &IoUring) -> Result<File> {
     debug!("Registered file with the uring");
     Ok(file)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_zero() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (conn, _) = listener.accept().unwrap();
+        let config = ClientConfig::from_query_params("cursor=0&requireHello=true").unwrap();
+        let permit = Limits::new(None, None, vec![], None).permit(None);
+        let file_len = AtomicU64::new(0);
+        let client = Client::new(conn, None, config, vec![], &file_len, permit).unwrap();
+        match &client.feed {
+            Feed::Hello(position) => assert_eq!(position.after, Timestamp(0)),
+            x => panic!("Expected to wait for hello, got {x}"),
+        }
+    }
+}
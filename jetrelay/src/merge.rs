@@ -0,0 +1,363 @@
+//! Merging several upstreams
+//!
+//! With `UPSTREAM_MODE=merge`, instead of failing over from one upstream to
+//! the next, we stay connected to all of them at once.  Each upstream is read
+//! by its own thread, and the frames are merged into a single stream, which
+//! the upstream copier writes to `jetrelay.dat` as usual.  That way an event
+//! which one upstream dropped still reaches our clients, so long as another
+//! upstream has it.
+//!
+//! Frames are held back for a short time, so that we can put them in
+//! timestamp order.  Each upstream assigns its own timestamps, so the same
+//! event can arrive with different timestamps from different upstreams.  We
+//! recognise duplicates by their content instead: a commit is identified by its
+//! DID, rev, and record path, and identity/account events by their DID and
+//! sequence number.
+//!
+//! Once a frame has been released, anything older which turns up afterwards is
+//! given the released frame's timestamp, so that `jetrelay.dat` stays in
+//! timestamp order.  These are the events which only a lagging upstream had,
+//! so we'd rather send them slightly late than not at all.
+
+use crate::datafile::WATERMARK;
+use crate::upstream::{
+    INGEST_PAUSED, INITIAL_BACKOFF, MAX_BACKOFF, PAUSE_POLL_INTERVAL, Timestamp, Watchdog,
+    lag_behind, with_cursor,
+};
+use anyhow::Result;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet, VecDeque};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::time::{Duration, Instant};
+use tracing::*;
+use url::Url;
+use wsclient::{Frame, OpCode};
+
+/// How long we wait for a frame's duplicates (or predecessors) to turn up
+const REORDER_WINDOW: Duration = Duration::from_millis(500);
+
+/// How long we remember the events we've seen, in terms of their timestamps
+/// (so that the clock stops while ingest is paused).  This needs to cover the
+/// skew between upstreams, and the overlap when one of them reconnects.
+pub const DEDUP_HORIZON: Duration = Duration::from_secs(2 * 60);
+
+/// How many frames the readers can get ahead of the merger
+const CHANNEL_SIZE: usize = 4096;
+
+/// Connects to all the upstreams, and returns the merged stream of frames.
+/// The readers reconnect by themselves, so the stream never ends.  `have` is
+/// the events we already have which the upstreams are going to send again.
+pub fn merged_frames(
+    urls: Vec<Url>,
+    watchdog: Watchdog,
+    have: HashSet<String>,
+) -> Result<impl Iterator<Item = Frame> + Send + 'static> {
+    let (tx, rx) = std::sync::mpsc::sync_channel(CHANNEL_SIZE);
+    for (i, url) in urls.into_iter().enumerate() {
+        let tx = tx.clone();
+        std::thread::Builder::new()
+            .name(format!("upstream_{i}"))
+            .spawn(move || read_upstream(i, url, watchdog, tx))?;
+    }
+    let watermark = Timestamp(WATERMARK.load(AtomicOrdering::Acquire));
+    let mut merger = Merger {
+        released: watermark,
+        ..Merger::default()
+    };
+    for id in have {
+        merger.seen_order.push_back((watermark, id.clone()));
+        merger.seen.insert(id);
+    }
+    Ok(std::iter::from_fn(move || merger.next(&rx)))
+}
+
+/// Runs until the merger goes away.  While ingest is paused we hang up, and
+/// then resume by cursor as usual.
+fn read_upstream(i: usize, url: Url, watchdog: Watchdog, tx: SyncSender<Frame>) {
+    let _g = info_span!("upstream reader", i).entered();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        while INGEST_PAUSED.load(AtomicOrdering::Relaxed) {
+            std::thread::sleep(PAUSE_POLL_INTERVAL);
+        }
+        let url = with_cursor(&url);
+        info!("Connecting to {url}");
+        let mut paused = false;
+        match wsclient::connect_websocket_with_timeout(&url, Some(watchdog.stall_timeout)) {
+            Ok(iter) => {
+                info!("Reading data from upstream");
+                crate::systemd::ready();
+                let connected_at = Instant::now();
+                for frame in iter {
+                    let frame = match frame {
+                        Ok(x) => x,
+                        Err(e) => {
+                            warn!("I/O error while reading from websocket: {e:#}");
+                            break;
+                        }
+                    };
+                    backoff = INITIAL_BACKOFF;
+                    if INGEST_PAUSED.load(AtomicOrdering::Relaxed) {
+                        // We'll get this frame again when we resume
+                        info!("Disconnecting while ingest is paused");
+                        paused = true;
+                        break;
+                    }
+                    // The copier only knows about the merged stream, so each
+                    // reader keeps an eye on its own upstream's lag
+                    let lag = (frame.opcode() == OpCode::Text)
+                        .then(|| std::str::from_utf8(frame.payload()).ok())
+                        .flatten()
+                        .map(|x| lag_behind(Timestamp(gjson::get(x, "time_us").u64())));
+                    if tx.send(frame).is_err() {
+                        return;
+                    }
+                    if let Some(lag) = lag
+                        && watchdog.is_lagging(connected_at, lag)
+                    {
+                        warn!("Upstream is {lag:?} behind");
+                        break;
+                    }
+                }
+                if paused {
+                    continue;
+                }
+                warn!("Lost the connection to upstream");
+            }
+            Err(e) => warn!("Couldn't connect to upstream: {e:#}"),
+        }
+        info!("Reconnecting in {backoff:?}");
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// A frame waiting to be released
+struct Pending {
+    timestamp: Timestamp,
+    /// Breaks ties between frames with the same timestamp
+    seq: u64,
+    arrived: Instant,
+    frame: Frame,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.seq).cmp(&(other.timestamp, other.seq))
+    }
+}
+
+#[derive(Default)]
+struct Merger {
+    pending: BinaryHeap<Reverse<Pending>>,
+    next_seq: u64,
+    /// The identities of the events we've seen recently, including the ones
+    /// which are still pending
+    seen: HashSet<String>,
+    seen_order: VecDeque<(Timestamp, String)>,
+    /// The newest timestamp we've seen from any upstream
+    latest: Timestamp,
+    /// The timestamp of the last frame we released.  Nothing we release after
+    /// it can be older.
+    released: Timestamp,
+}
+
+impl Merger {
+    fn next(&mut self, rx: &Receiver<Frame>) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.pop_ready(Instant::now()) {
+                return Some(frame);
+            }
+            let timeout = match self.pending.peek() {
+                Some(Reverse(x)) => REORDER_WINDOW.saturating_sub(x.arrived.elapsed()),
+                None => REORDER_WINDOW,
+            };
+            match rx.recv_timeout(timeout) {
+                Ok(frame) => self.push(frame, Instant::now()),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    let Reverse(x) = self.pending.pop()?;
+                    return Some(self.release(x));
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, frame: Frame, now: Instant) {
+        if frame.opcode() != OpCode::Text {
+            return; // Pings etc. are no use to the copier
+        }
+        let Some((timestamp, id)) = std::str::from_utf8(frame.payload()).ok().and_then(identify)
+        else {
+            warn!("Couldn't identify frame: {:?}", frame.bytes);
+            return;
+        };
+
+        self.latest = self.latest.max(timestamp);
+        while let Some((t, _)) = self.seen_order.front()
+            && *t + DEDUP_HORIZON < self.latest
+        {
+            let (_, id) = self.seen_order.pop_front().unwrap();
+            self.seen.remove(&id);
+        }
+        if let Some(id) = id {
+            if self.seen.contains(&id) {
+                trace!("Dropping a duplicate: {id}");
+                return;
+            }
+            self.seen.insert(id.clone());
+            self.seen_order.push_back((self.latest, id));
+        }
+
+        self.pending.push(Reverse(Pending {
+            timestamp,
+            seq: self.next_seq,
+            arrived: now,
+            frame,
+        }));
+        self.next_seq += 1;
+    }
+
+    /// A frame is released once it's been waiting for the whole window, or
+    /// something a whole window newer has turned up
+    fn pop_ready(&mut self, now: Instant) -> Option<Frame> {
+        let Reverse(x) = self.pending.peek()?;
+        let ready = x.timestamp + REORDER_WINDOW <= self.latest
+            || now.duration_since(x.arrived) >= REORDER_WINDOW;
+        ready.then(|| {
+            let Reverse(x) = self.pending.pop().unwrap();
+            self.release(x)
+        })
+    }
+
+    fn release(&mut self, x: Pending) -> Frame {
+        if x.timestamp >= self.released {
+            self.released = x.timestamp;
+            return x.frame;
+        }
+        debug!(
+            "Late frame at ts={:?}, moving it up to ts={:?}",
+            x.timestamp, self.released
+        );
+        // `identify` has already found the timestamp, so this can't fail
+        with_timestamp(&x.frame, self.released).unwrap_or(x.frame)
+    }
+}
+
+/// A copy of a text frame with its `time_us` replaced
+fn with_timestamp(frame: &Frame, timestamp: Timestamp) -> Option<Frame> {
+    let payload = std::str::from_utf8(frame.payload()).ok()?;
+    let old = gjson::get(payload, "time_us");
+    let old = old.json();
+    // `old` points into `payload`
+    let start = (old.as_ptr() as usize).checked_sub(payload.as_ptr() as usize)?;
+    let end = start + old.len();
+    if payload.get(start..end) != Some(old) {
+        return None;
+    }
+    let payload = format!("{}{}{}", &payload[..start], timestamp.0, &payload[end..]);
+    Some(Frame::new(OpCode::Text, payload.as_bytes()))
+}
+
+/// The timestamp of an event, plus something which identifies it regardless of
+/// which upstream it came from (if it's a kind of event we know about)
+pub fn identify(payload: &str) -> Option<(Timestamp, Option<String>)> {
+    let timestamp = gjson::get(payload, "time_us");
+    if timestamp.kind() != gjson::Kind::Number {
+        return None;
+    }
+    let timestamp = Timestamp(timestamp.u64());
+    let did = gjson::get(payload, "did");
+    let kind = gjson::get(payload, "kind");
+    let id = match kind.str() {
+        // A single commit can contain several writes, so the rev isn't enough
+        "commit" => {
+            let commit = gjson::get(payload, "commit");
+            let rev = commit.get("rev");
+            let collection = commit.get("collection");
+            let rkey = commit.get("rkey");
+            Some(format!(
+                "{} {} {}/{}",
+                did.str(),
+                rev.str(),
+                collection.str(),
+                rkey.str()
+            ))
+        }
+        "identity" | "account" => {
+            let seq = gjson::get(payload, kind.str()).get("seq").u64();
+            Some(format!("{} {} {seq}", did.str(), kind.str()))
+        }
+        _ => None,
+    };
+    Some((timestamp, id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(ts: u64, rkey: &str) -> Frame {
+        let json = format!(
+            r#"{{"did":"did:plc:a","time_us":{ts},"kind":"commit","commit":{{"rev":"3l","operation":"create","collection":"app.bsky.feed.post","rkey":"{rkey}"}}}}"#
+        );
+        Frame::new(OpCode::Text, json.as_bytes())
+    }
+
+    #[test]
+    fn merge() {
+        let mut merger = Merger::default();
+        let t0 = Instant::now();
+        // Two upstreams, one of which is running a bit behind and dropped "b"
+        merger.push(commit(1_000_000, "a"), t0);
+        merger.push(commit(1_000_100, "b"), t0);
+        merger.push(commit(1_000_050, "a"), t0);
+        merger.push(commit(1_000_200, "c"), t0);
+        merger.push(commit(1_000_210, "c"), t0);
+        assert!(merger.pop_ready(t0).is_none());
+
+        let later = t0 + REORDER_WINDOW;
+        let mut out = vec![];
+        while let Some(frame) = merger.pop_ready(later) {
+            out.push(identify(std::str::from_utf8(frame.payload()).unwrap()).unwrap());
+        }
+        let timestamps: Vec<u64> = out.iter().map(|x| x.0.0).collect();
+        assert_eq!(timestamps, [1_000_000, 1_000_100, 1_000_200]);
+    }
+
+    #[test]
+    fn late_frame() {
+        let mut merger = Merger::default();
+        let t0 = Instant::now();
+        merger.push(commit(1_000_000, "a"), t0);
+        merger.push(commit(1_000_100, "b"), t0);
+        let later = t0 + REORDER_WINDOW;
+        assert!(merger.pop_ready(later).is_some());
+        assert!(merger.pop_ready(later).is_some());
+
+        // The upstream which had "c" was lagging by more than the window.  We
+        // still send it, but not out of order.
+        merger.push(commit(1_000_050, "c"), later);
+        assert!(merger.pop_ready(later).is_none());
+        let frame = merger.pop_ready(later + REORDER_WINDOW).unwrap();
+        let payload = std::str::from_utf8(frame.payload()).unwrap();
+        let (timestamp, id) = identify(payload).unwrap();
+        assert_eq!(timestamp, Timestamp(1_000_100));
+        assert!(id.unwrap().ends_with("/c"));
+    }
+}
@@ -0,0 +1,234 @@
+//! Prometheus metrics
+//!
+//! Most of these are counters which the rest of the code bumps as it goes.
+//! The ones which describe the clients belong to the runloop, so it publishes
+//! a summary each time round (see [`record_clients`]).  Everything else is
+//! read straight out of the indices when the metrics are scraped.
+//!
+//! The metrics are served over HTTP; see [`crate::http`].
+
+use crate::dids::DID_INDEX;
+use crate::io::USER_DATA_KINDS;
+use crate::upstream::{
+    ACTIVE_UPSTREAM, FRAME_SIZES, INDEX, UPSTREAM_LAG_US, UPSTREAM_SWITCHES, allocated_bytes,
+};
+use crate::{Client, ClientId};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
+use std::fs::File;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the client lag buckets, in seconds
+const LAG_BUCKETS: [u64; 5] = [1, 10, 60, 600, 3600];
+
+pub static CLIENTS: AtomicU64 = AtomicU64::new(0);
+/// The number of clients in each of `LAG_BUCKETS`, plus one for everyone else
+pub static CLIENTS_BY_LAG: [AtomicU64; LAG_BUCKETS.len() + 1] =
+    [const { AtomicU64::new(0) }; LAG_BUCKETS.len() + 1];
+
+/// Bytes spliced from the data files into pipes
+pub static BYTES_FILLED: AtomicU64 = AtomicU64::new(0);
+/// Bytes spliced from pipes into sockets
+pub static BYTES_DRAINED: AtomicU64 = AtomicU64::new(0);
+
+/// Indexed by `UserData` kind
+pub static SUBMISSIONS: [AtomicU64; USER_DATA_KINDS.len()] =
+    [const { AtomicU64::new(0) }; USER_DATA_KINDS.len()];
+pub static COMPLETIONS: [AtomicU64; USER_DATA_KINDS.len()] =
+    [const { AtomicU64::new(0) }; USER_DATA_KINDS.len()];
+
+/// Indexed by `HandshakeOutcome`
+static HANDSHAKES: [AtomicU64; HandshakeOutcome::ALL.len()] =
+    [const { AtomicU64::new(0) }; HandshakeOutcome::ALL.len()];
+
+/// Frames written to `jetrelay.dat` (so not counting repeats)
+pub static UPSTREAM_FRAMES: AtomicU64 = AtomicU64::new(0);
+pub static UPSTREAM_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Across all the data files
+pub static HOLE_PUNCHES: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy)]
+pub enum HandshakeOutcome {
+    Success,
+    /// The client took too long
+    Timeout,
+    /// The socket failed, or the client hung up
+    Io,
+    /// The client sent something which wasn't a valid handshake request
+    BadRequest,
+    /// The handshake went through, but we couldn't set up the client
+    Rejected,
+    /// The client was turned away by the connection limits
+    Limited,
+}
+
+impl HandshakeOutcome {
+    const ALL: [HandshakeOutcome; 6] = [
+        HandshakeOutcome::Success,
+        HandshakeOutcome::Timeout,
+        HandshakeOutcome::Io,
+        HandshakeOutcome::BadRequest,
+        HandshakeOutcome::Rejected,
+        HandshakeOutcome::Limited,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            HandshakeOutcome::Success => "success",
+            HandshakeOutcome::Timeout => "timeout",
+            HandshakeOutcome::Io => "io",
+            HandshakeOutcome::BadRequest => "bad_request",
+            HandshakeOutcome::Rejected => "rejected",
+            HandshakeOutcome::Limited => "limited",
+        }
+    }
+}
+
+pub fn count_handshake(outcome: HandshakeOutcome) {
+    HANDSHAKES[outcome as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Publish the number of clients, and how far behind they are.  The lags are
+/// the ones last measured by `crate::policy::check_slow_consumer`, which is at
+/// most a second ago.
+pub fn record_clients(clients: &HashMap<ClientId, Client>) {
+    let mut by_lag = [0; LAG_BUCKETS.len() + 1];
+    for client in clients.values() {
+        let i = LAG_BUCKETS.partition_point(|x| Duration::from_secs(*x) < client.lag);
+        by_lag[i] += 1;
+    }
+    CLIENTS.store(clients.len() as u64, Ordering::Relaxed);
+    for (x, n) in CLIENTS_BY_LAG.iter().zip(by_lag) {
+        x.store(n, Ordering::Relaxed);
+    }
+}
+
+/// Writes metrics in the Prometheus text format
+#[derive(Default)]
+struct Exposition(String);
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.0, "# HELP {name} {help}").unwrap();
+        writeln!(self.0, "# TYPE {name} {kind}").unwrap();
+    }
+
+    fn value(&mut self, name: &str, labels: &str, value: impl Display) {
+        if labels.is_empty() {
+            writeln!(self.0, "{name} {value}").unwrap();
+        } else {
+            writeln!(self.0, "{name}{{{labels}}} {value}").unwrap();
+        }
+    }
+
+    fn single(&mut self, name: &str, kind: &str, help: &str, value: impl Display) {
+        self.header(name, kind, help);
+        self.value(name, "", value);
+    }
+}
+
+/// All the metrics, in the Prometheus text format.  `file` is `jetrelay.dat`.
+pub fn render(file: &File) -> Result<String> {
+    let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+    let mut out = Exposition::default();
+
+    out.single(
+        "jetrelay_clients",
+        "gauge",
+        "Connected clients",
+        load(&CLIENTS),
+    );
+    let name = "jetrelay_clients_by_lag";
+    out.header(name, "gauge", "Clients at most `le` seconds behind live");
+    let mut cumulative = 0;
+    for (i, n) in CLIENTS_BY_LAG.iter().enumerate() {
+        cumulative += load(n);
+        match LAG_BUCKETS.get(i) {
+            Some(le) => out.value(name, &format!("le=\"{le}\""), cumulative),
+            None => out.value(name, "le=\"+Inf\"", cumulative),
+        }
+    }
+
+    let name = "jetrelay_spliced_bytes_total";
+    out.header(
+        name,
+        "counter",
+        "Bytes spliced into pipes (fill) and sockets (drain)",
+    );
+    out.value(name, "op=\"fill\"", load(&BYTES_FILLED));
+    out.value(name, "op=\"drain\"", load(&BYTES_DRAINED));
+
+    let name = "jetrelay_uring_submissions_total";
+    out.header(name, "counter", "io_uring submissions");
+    for (kind, n) in USER_DATA_KINDS.iter().zip(&SUBMISSIONS) {
+        out.value(name, &format!("kind=\"{kind}\""), load(n));
+    }
+    let name = "jetrelay_uring_completions_total";
+    out.header(name, "counter", "io_uring completions");
+    for (kind, n) in USER_DATA_KINDS.iter().zip(&COMPLETIONS) {
+        out.value(name, &format!("kind=\"{kind}\""), load(n));
+    }
+
+    let name = "jetrelay_handshakes_total";
+    out.header(name, "counter", "Websocket handshakes, by outcome");
+    for outcome in HandshakeOutcome::ALL {
+        let n = load(&HANDSHAKES[outcome as usize]);
+        out.value(name, &format!("result=\"{}\"", outcome.label()), n);
+    }
+
+    out.single(
+        "jetrelay_upstream_frames_total",
+        "counter",
+        "Frames received from upstream and written to disk",
+        load(&UPSTREAM_FRAMES),
+    );
+    out.single(
+        "jetrelay_upstream_bytes_total",
+        "counter",
+        "Bytes received from upstream and written to disk",
+        load(&UPSTREAM_BYTES),
+    );
+    out.single(
+        "jetrelay_upstream_lag_seconds",
+        "gauge",
+        "How far behind the wall clock the last event from upstream was",
+        load(&UPSTREAM_LAG_US) as f64 / 1e6,
+    );
+    out.single(
+        "jetrelay_upstream_active",
+        "gauge",
+        "The index (into UPSTREAM_URL) of the upstream we're reading from",
+        ACTIVE_UPSTREAM.load(Ordering::Relaxed),
+    );
+    out.single(
+        "jetrelay_upstream_switches_total",
+        "counter",
+        "Switches from one upstream to another",
+        load(&UPSTREAM_SWITCHES),
+    );
+
+    let name = "jetrelay_index_entries";
+    out.header(name, "gauge", "Entries in the in-memory indices");
+    let n = INDEX.lock().unwrap().len();
+    out.value(name, "index=\"timestamps\"", n);
+    let n = FRAME_SIZES.lock().unwrap().len();
+    out.value(name, "index=\"frames\"", n);
+    let n = DID_INDEX.lock().unwrap().len();
+    out.value(name, "index=\"dids\"", n);
+    out.single(
+        "jetrelay_retained_bytes",
+        "gauge",
+        "Disk space used by the data files",
+        allocated_bytes(file)?,
+    );
+    out.single(
+        "jetrelay_hole_punches_total",
+        "counter",
+        "Times old data has been dropped from a data file",
+        load(&HOLE_PUNCHES),
+    );
+    Ok(out.0)
+}
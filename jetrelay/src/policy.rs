@@ -0,0 +1,332 @@
+//! What to do about clients which fall behind
+//!
+//! When old data is dropped, the upstream copier punches a hole in the file
+//! and publishes the new low-water mark.  A client whose position is below the
+//! mark would otherwise splice zeroes out of the hole, so the runloop checks
+//! each client against it.  Depending on `DROPPED_DATA_POLICY`, such clients
+//! are either sent a close frame, or skipped ahead to the oldest data we still
+//! have (with a marker event to tell them that they've missed something).
+//!
+//! A client which is in the middle of a frame when its data is dropped can't
+//! be sent anything else, so it's simply disconnected.
+//!
+//! A fill which is already in flight when the data is dropped may not have
+//! read the file yet (eg. if it's waiting for room in the pipe).  So the data
+//! is only punched out of the file once the runloop has been round all the
+//! clients, cancelling any such fills, and none are left; see
+//! [`allow_punching`].
+//!
+//! Separately, there's the slow consumer policy, which applies to clients
+//! which are too far behind live, measured either in bytes of `jetrelay.dat`
+//! or in time.  We can disconnect them, skip them ahead to live (again with a
+//! marker event), or just keep going.  The deployment sets the default, and
+//! clients can override it with query params.
+
+use crate::control::send_control_frame;
+use crate::datafile::WATERMARK;
+use crate::handshake::Compression;
+use crate::upstream::{LOW_WATER, PUNCH_SAFE, Timestamp, resolve_cursor, timestamp_before};
+use crate::{Client, ClientId, Feed};
+use anyhow::{Result, bail};
+use rustix::net::Shutdown;
+use rustix_uring::squeue;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::*;
+use wsclient::OpCode;
+
+/// The close code we send to clients whose data has been dropped.  Codes in
+/// the 4000s are for private use.
+const DATA_DROPPED_CODE: u16 = 4000;
+/// The close code we send to slow consumers
+const TOO_SLOW_CODE: u16 = 4001;
+
+/// Working out how far behind a client is takes a few lookups, so we don't do
+/// it every time round the runloop
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Policy {
+    pub keepalive_timeout: Duration,
+    pub dropped_data: DroppedDataPolicy,
+    pub slow_consumer: SlowConsumer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DroppedDataPolicy {
+    Close,
+    Skip,
+}
+
+impl std::str::FromStr for DroppedDataPolicy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "close" => Ok(DroppedDataPolicy::Close),
+            "skip" => Ok(DroppedDataPolicy::Skip),
+            x => bail!("Expected \"close\" or \"skip\", saw {x:?}"),
+        }
+    }
+}
+
+/// What to do about clients which are too far behind live.  Any of these can
+/// be unset, in which case the deployment's setting applies.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlowConsumer {
+    pub action: Option<SlowConsumerAction>,
+    pub max_lag: Option<Duration>,
+    pub max_lag_bytes: Option<u64>,
+}
+
+impl SlowConsumer {
+    fn or(self, other: SlowConsumer) -> SlowConsumer {
+        SlowConsumer {
+            action: self.action.or(other.action),
+            max_lag: self.max_lag.or(other.max_lag),
+            max_lag_bytes: self.max_lag_bytes.or(other.max_lag_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerAction {
+    Disconnect,
+    Skip,
+    Keep,
+}
+
+impl std::str::FromStr for SlowConsumerAction {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disconnect" => Ok(SlowConsumerAction::Disconnect),
+            "skip" => Ok(SlowConsumerAction::Skip),
+            "keep" => Ok(SlowConsumerAction::Keep),
+            x => bail!("Expected \"disconnect\", \"skip\", or \"keep\", saw {x:?}"),
+        }
+    }
+}
+
+impl fmt::Display for SlowConsumerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            SlowConsumerAction::Disconnect => "disconnect",
+            SlowConsumerAction::Skip => "skip",
+            SlowConsumerAction::Keep => "keep",
+        })
+    }
+}
+
+enum Hole {
+    /// The client's next frame has been dropped
+    AtBoundary,
+    /// The rest of the frame the client is in the middle of has been dropped
+    MidFrame,
+}
+
+fn find_hole(client: &Client) -> Option<Hole> {
+    let low_water = LOW_WATER.load(Ordering::Acquire);
+    match &client.feed {
+        Feed::Hello(_) => None,
+        Feed::All if client.offset < low_water => Some(if client.offset < client.frame_end {
+            Hole::MidFrame
+        } else {
+            Hole::AtBoundary
+        }),
+        Feed::Dids(dids) if client.offset < low_water => Some(if dids.run_end.is_some() {
+            Hole::MidFrame
+        } else {
+            Hole::AtBoundary
+        }),
+        Feed::All | Feed::Dids(_) => None,
+        Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+            match &interleaver.run {
+                Some(run) if run.offset < run.file.low_water.load(Ordering::Acquire) => {
+                    Some(if run.offset < run.frame_end {
+                        Hole::MidFrame
+                    } else {
+                        Hole::AtBoundary
+                    })
+                }
+                Some(_) => None,
+                None => interleaver.fell_behind().then_some(Hole::AtBoundary),
+            }
+        }
+    }
+}
+
+/// Deal with the client if its data has been dropped.  Returns true if we
+/// mustn't fill its pipe from the data files (yet).
+pub fn check_dropped_data(
+    sqes: &mut Vec<squeue::Entry>,
+    client_id: ClientId,
+    client: &mut Client,
+    policy: DroppedDataPolicy,
+) -> bool {
+    let Some(hole) = find_hole(client) else {
+        return false;
+    };
+    if client.copy_in_flight {
+        // The fill may not have read the file yet.  Once it's been cancelled
+        // (or has finished), we can deal with the client as usual.
+        if !client.cancel_in_flight {
+            debug!("Cancelling a fill from dropped data");
+            sqes.push(crate::io::cancel_fill(client_id));
+            client.cancel_in_flight = true;
+        }
+        return true;
+    }
+    match hole {
+        // It's going away anyway, but it can't finish the frame it's on
+        Hole::MidFrame if client.closing => {
+            let _ = rustix::net::shutdown(&client.conn, Shutdown::Both);
+        }
+        _ if client.closing => (),
+        Hole::MidFrame => {
+            warn!("Data was dropped while the client was half-way through it; disconnecting");
+            client.closing = true;
+            client.outbox.clear();
+            // The poll will notice and remove the client
+            let _ = rustix::net::shutdown(&client.conn, Shutdown::Both);
+        }
+        Hole::AtBoundary if policy == DroppedDataPolicy::Close => {
+            warn!("The client's data has been dropped; closing the connection");
+            if let Some(interleaver) = client.feed.interleaver() {
+                interleaver.run = None;
+            }
+            let reason = "Your position in the stream is no longer available";
+            crate::control::close(client, DATA_DROPPED_CODE, reason);
+        }
+        Hole::AtBoundary => {
+            let after = match &mut client.feed {
+                Feed::All | Feed::Dids(_) => {
+                    client.offset = LOW_WATER.load(Ordering::Acquire);
+                    client.frame_end = client.offset;
+                    crate::upstream::timestamp_before(client.offset)
+                }
+                Feed::Collections(interleaver)
+                | Feed::Zstd(interleaver)
+                | Feed::Fsst(interleaver) => {
+                    interleaver.run = None;
+                    interleaver.skip_dropped_data()
+                }
+                Feed::Hello(_) => unreachable!(),
+            };
+            warn!("The client's data has been dropped; skipping ahead to {after:?}");
+            send_gap_marker(client, after);
+            return false;
+        }
+    }
+    true
+}
+
+/// Once none of the clients have a fill in flight from dropped data, the
+/// upstream copier can punch out the data dropped in `generation`.  This has
+/// to be loaded before `check_dropped_data` is called for each client, so that
+/// any fills submitted since then start at or after its low-water marks.
+pub fn allow_punching(generation: u64, clients: &HashMap<ClientId, Client>) {
+    let reading_hole = |x: &Client| x.copy_in_flight && find_hole(x).is_some();
+    if !clients.values().any(reading_hole) {
+        PUNCH_SAFE.store(generation, Ordering::Release);
+    }
+}
+
+/// Deal with the client if it's too far behind live.  This also keeps
+/// `Client::lag` up to date, for the metrics and the dashboard, even for
+/// clients which the policy doesn't apply to.
+pub fn check_slow_consumer(client: &mut Client, policy: SlowConsumer, file_len: u64) {
+    let mut measured = None;
+    if client.last_lag_check.elapsed() >= LAG_CHECK_INTERVAL
+        && let Some(x) = lag(client, file_len)
+    {
+        client.last_lag_check = Instant::now();
+        client.lag = x.0;
+        measured = Some(x);
+    }
+    if client.skip_to_live {
+        skip_to_live(client, file_len);
+        return;
+    }
+    if client.closing {
+        return;
+    }
+    let Some((lag, lag_bytes)) = measured else {
+        return;
+    };
+    let policy = client.slow_consumer.or(policy);
+    let action = policy.action.unwrap_or(SlowConsumerAction::Keep);
+    if action == SlowConsumerAction::Keep {
+        return;
+    }
+    let too_slow = policy.max_lag.is_some_and(|x| lag > x)
+        || policy.max_lag_bytes.is_some_and(|x| lag_bytes > x);
+    if !too_slow {
+        return;
+    }
+    match action {
+        SlowConsumerAction::Disconnect => {
+            warn!("Client is {lag:?} ({lag_bytes} bytes) behind; disconnecting");
+            crate::control::close(client, TOO_SLOW_CODE, "Too far behind");
+        }
+        SlowConsumerAction::Skip => {
+            warn!("Client is {lag:?} ({lag_bytes} bytes) behind; skipping ahead");
+            client.skip_to_live = true;
+            skip_to_live(client, file_len);
+        }
+        SlowConsumerAction::Keep => (),
+    }
+}
+
+/// How far behind live the client is, in time and in bytes of `jetrelay.dat`.
+/// Returns `None` if we're in the middle of sending a frame.
+fn lag(client: &mut Client, file_len: u64) -> Option<(Duration, u64)> {
+    // Unlike `Client::position`, this works in the middle of a frame, or
+    // while a fill is in flight
+    let (after, offset) = match &mut client.feed {
+        Feed::Hello(_) => return None,
+        Feed::All | Feed::Dids(_) => (timestamp_before(client.offset), client.offset),
+        Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+            let after = match &interleaver.run {
+                Some(run) => run.file.timestamp_before(run.offset),
+                None => interleaver.position(),
+            };
+            let offset = resolve_cursor(after + Duration::from_micros(1));
+            (after, offset.unwrap_or(file_len))
+        }
+    };
+    let watermark = WATERMARK.load(Ordering::Acquire);
+    let lag = Duration::from_micros(watermark.saturating_sub(after.0));
+    Some((lag, file_len.saturating_sub(offset)))
+}
+
+/// Skip ahead to the end of the file, once we're at a frame boundary
+fn skip_to_live(client: &mut Client, file_len: u64) {
+    if !client.at_frame_boundary(file_len) {
+        return; // We'll be back
+    }
+    client.skip_to_live = false;
+    let watermark = Timestamp(WATERMARK.load(Ordering::Acquire));
+    match &mut client.feed {
+        Feed::Hello(_) => return,
+        Feed::All | Feed::Dids(_) => {
+            client.offset = file_len;
+            client.frame_end = file_len;
+        }
+        Feed::Collections(interleaver) | Feed::Zstd(interleaver) | Feed::Fsst(interleaver) => {
+            interleaver.skip_to(watermark);
+        }
+    }
+    send_gap_marker(client, watermark);
+}
+
+/// Tells the client that it's missed some events.  Looks enough like a
+/// jetstream event that clients can use `time_us` as a cursor.  Compressed
+/// clients expect every event to be compressed, so they don't get one.
+fn send_gap_marker(client: &mut Client, after: Timestamp) {
+    if client.compression != Compression::None {
+        return;
+    }
+    let msg = format!(r#"{{"kind":"gap","time_us":{}}}"#, after.0);
+    send_control_frame(client, OpCode::Text, msg.as_bytes());
+}
@@ -0,0 +1,145 @@
+//! The PROXY protocol
+//!
+//! When jetrelay sits behind a load balancer (eg. haproxy or nginx, doing
+//! TLS), every connection appears to come from the load balancer.  Load
+//! balancers can tell us who they're forwarding for by sending a PROXY
+//! protocol header before the client's own data.  We accept both versions:
+//! v1 is a line of text, and v2 is binary.
+//!
+//! Anyone can send one of these, so we only believe them from the addresses in
+//! `TRUSTED_PROXIES`.  From those, the header is optional.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use anyhow::{Context, Result, bail, ensure};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Including the CRLF
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// We need more data to tell
+    Incomplete,
+    /// The connection doesn't start with a PROXY header
+    Absent,
+    /// The header is `len` bytes long.  `source` is the client's address,
+    /// unless the proxy didn't say (eg. for health checks).
+    Header {
+        len: usize,
+        source: Option<SocketAddr>,
+    },
+}
+
+/// Look for a PROXY header at the start of `buf`
+pub fn parse(buf: &[u8]) -> Result<Parsed> {
+    if is_prefix(buf, V1_PREFIX) {
+        parse_v1(buf)
+    } else if is_prefix(buf, V2_SIGNATURE) {
+        parse_v2(buf)
+    } else {
+        Ok(Parsed::Absent)
+    }
+}
+
+/// Whether `buf` and `prefix` agree, as far as they go
+fn is_prefix(buf: &[u8], prefix: &[u8]) -> bool {
+    let n = buf.len().min(prefix.len());
+    buf[..n] == prefix[..n]
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Parsed> {
+    let window = &buf[..buf.len().min(V1_MAX_LEN)];
+    let Some(end) = window.windows(2).position(|x| x == b"\r\n") else {
+        ensure!(buf.len() < V1_MAX_LEN, "PROXY header is too long");
+        return Ok(Parsed::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[..end]).context("Bad PROXY header")?;
+    let mut fields = line.split(' ').skip(1);
+    let source = match fields.next() {
+        Some("TCP4" | "TCP6") => {
+            let mut next = || fields.next().context("Truncated PROXY header");
+            let src: IpAddr = next()?.parse()?;
+            let _dst: IpAddr = next()?.parse()?;
+            let src_port: u16 = next()?.parse()?;
+            Some(SocketAddr::new(src, src_port))
+        }
+        Some("UNKNOWN") => None,
+        x => bail!("Bad PROXY header: unknown protocol {x:?}"),
+    };
+    Ok(Parsed::Header {
+        len: end + 2,
+        source,
+    })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+    let version = buf[12] >> 4;
+    let command = buf[12] & 0xf;
+    ensure!(version == 2, "Bad PROXY header: version {version}");
+    let family = buf[13] >> 4;
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+    let source = match (command, family) {
+        // LOCAL: the proxy is talking to us on its own account
+        (0, _) => None,
+        (1, 1) => {
+            ensure!(addrs.len() >= 12, "Truncated PROXY header");
+            let ip = Ipv4Addr::from_bits(u32::from_be_bytes(addrs[0..4].try_into().unwrap()));
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (1, 2) => {
+            ensure!(addrs.len() >= 36, "Truncated PROXY header");
+            let ip = Ipv6Addr::from_bits(u128::from_be_bytes(addrs[0..16].try_into().unwrap()));
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // Unix sockets, or unspecified
+        (1, _) => None,
+        _ => bail!("Bad PROXY header: command {command}"),
+    };
+    Ok(Parsed::Header { len, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let source = Some("192.0.2.1:56324".parse().unwrap());
+        assert_eq!(parse(buf).unwrap(), Parsed::Header { len: 45, source });
+        assert_eq!(parse(&buf[..20]).unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(b"PRO").unwrap(), Parsed::Incomplete);
+        let buf = b"PROXY UNKNOWN\r\n";
+        let expected = Parsed::Header {
+            len: 15,
+            source: None,
+        };
+        assert_eq!(parse(buf).unwrap(), expected);
+        assert_eq!(parse(b"GET / HTTP/1.1\r\n").unwrap(), Parsed::Absent);
+        assert!(parse(b"PROXY TCP5 x\r\n").is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        buf.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        let source = Some("192.0.2.1:56324".parse().unwrap());
+        assert_eq!(parse(&buf).unwrap(), Parsed::Header { len: 28, source });
+        assert_eq!(parse(&buf[..20]).unwrap(), Parsed::Incomplete);
+        assert_eq!(parse(&buf[..5]).unwrap(), Parsed::Incomplete);
+    }
+}
@@ -0,0 +1,101 @@
+//! Shutting down gracefully
+//!
+//! SIGTERM and SIGINT arrive at the runloop via a signalfd (see
+//! [`crate::io::Signals`]).  When one does, we:
+//!
+//! 1. stop accepting connections, and stop reading from upstream, so that
+//!    `jetrelay.dat` stops growing;
+//! 2. give the clients up to `SHUTDOWN_GRACE_PERIOD` to catch up with the end
+//!    of the file;
+//! 3. send every client a 1001 ("going away") close frame, and wait briefly
+//!    for them to go; and
+//! 4. fsync `jetrelay.dat` and exit.
+//!
+//! A second signal skips the rest of the grace period.  The index isn't
+//! persisted (it's rebuilt from `jetrelay.dat` at startup), so the data file
+//! is the only thing which needs flushing.
+
+use crate::control::CLOSE_TIMEOUT;
+use crate::upstream::INGEST_PAUSED;
+use crate::{Client, ClientId, Feed};
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// How long we give clients to catch up, by default
+pub const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+const GOING_AWAY_CODE: u16 = 1001;
+const GOING_AWAY_REASON: &str = "Server is shutting down";
+
+#[derive(Debug)]
+pub struct Shutdown {
+    /// Until then, clients which are still catching up get to carry on
+    drain_deadline: Instant,
+    /// Set once we've sent the close frames
+    close_deadline: Option<Instant>,
+}
+
+impl Shutdown {
+    /// The caller should also stop accepting connections
+    pub fn start(grace_period: Duration) -> Shutdown {
+        info!("Shutting down; letting clients drain for up to {grace_period:?}");
+        crate::systemd::stopping();
+        INGEST_PAUSED.store(true, Ordering::Relaxed);
+        Shutdown {
+            drain_deadline: Instant::now() + grace_period,
+            close_deadline: None,
+        }
+    }
+
+    /// Cut the grace period short
+    pub fn hurry(&mut self) {
+        if self.close_deadline.is_none() {
+            info!("Got a second signal; not waiting for clients to drain");
+            self.drain_deadline = Instant::now();
+        }
+    }
+
+    /// Called by the runloop.  Returns `true` once it's time to exit.
+    pub fn advance(&mut self, clients: &mut HashMap<ClientId, Client>, file_len: u64) -> bool {
+        if self.close_deadline.is_none() {
+            let drained = clients.values_mut().all(|x| caught_up(x, file_len));
+            if !drained && Instant::now() < self.drain_deadline {
+                return false;
+            }
+            if !drained {
+                warn!("Some clients didn't catch up within the grace period");
+            }
+            info!("Closing {} connections", clients.len());
+            for (client_id, client) in clients.iter_mut() {
+                if !client.closing {
+                    let _g = debug_span!("", client_id).entered();
+                    crate::control::close(client, GOING_AWAY_CODE, GOING_AWAY_REASON);
+                }
+            }
+            self.close_deadline = Some(Instant::now() + CLOSE_TIMEOUT);
+        }
+        if clients.is_empty() {
+            return true;
+        }
+        let timed_out = self.close_deadline.is_some_and(|x| Instant::now() >= x);
+        if timed_out {
+            warn!("{} clients didn't close in time", clients.len());
+        }
+        timed_out
+    }
+}
+
+/// Whether we've sent the client everything there is to send
+fn caught_up(client: &mut Client, file_len: u64) -> bool {
+    if client.closing || matches!(client.feed, Feed::Hello(_)) {
+        return true;
+    }
+    if client.send_in_flight || client.bytes_in_pipe > 0 {
+        return false;
+    }
+    client
+        .position(file_len)
+        .is_some_and(|x| x.offset >= file_len)
+}
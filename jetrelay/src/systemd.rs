@@ -0,0 +1,151 @@
+//! Talking to systemd
+//!
+//! When run with `Type=notify`, we tell systemd when we're ready (once the
+//! listener is bound and we've connected to upstream), keep its watchdog fed
+//! from the runloop, and give it a status line to show in `systemctl status`.
+//! This is the sd_notify protocol: datagrams sent to `$NOTIFY_SOCKET`.
+//!
+//! We also support socket activation: if systemd passes us a listening socket
+//! (via `$LISTEN_FDS`), we use that instead of binding `JETRELAY_PORT`.
+
+use crate::upstream::UPSTREAM_LAG_US;
+use anyhow::{Context, Result, ensure};
+use rustix::fd::{FromRawFd, OwnedFd};
+use rustix::io::FdFlags;
+use std::net::TcpListener;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// The first fd passed by systemd
+const LISTEN_FDS_START: i32 = 3;
+
+/// How often to update the status line
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+/// How much more time `keep_alive()` asks for
+const KEEP_ALIVE_EXTENSION: Duration = Duration::from_secs(30);
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Send a message to systemd.  Does nothing if we weren't started by systemd
+/// (or it doesn't want to hear from us).
+fn notify(msg: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let send = || -> Result<()> {
+        // Paths starting with '@' are in the abstract namespace
+        let addr = match path.as_encoded_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(msg.as_bytes(), &addr)?;
+        Ok(())
+    };
+    if let Err(e) = send() {
+        debug!("Couldn't notify systemd: {e:#}");
+    }
+}
+
+/// Tell systemd that we're up and running.  Only the first call does
+/// anything.
+pub fn ready() {
+    if !READY.swap(true, Ordering::Relaxed) {
+        info!("Notifying systemd that we're ready");
+        notify("READY=1");
+    }
+}
+
+/// Tell systemd that we're shutting down
+pub fn stopping() {
+    notify("STOPPING=1");
+}
+
+/// Feed the watchdog, and give us more time to start up, for when we're busy
+/// with something other than the runloop.  Call this every few seconds.
+pub fn keep_alive() {
+    let usec = KEEP_ALIVE_EXTENSION.as_micros();
+    notify(&format!("WATCHDOG=1\nEXTEND_TIMEOUT_USEC={usec}"));
+}
+
+/// Tell systemd that another process is taking over from us
+pub fn main_pid(pid: u32) {
+    notify(&format!("MAINPID={pid}"));
+}
+
+/// The listening socket which systemd passed us, if any
+pub fn listen_fds() -> Result<Option<TcpListener>> {
+    let var = "LISTEN_PID";
+    let Ok(pid) = std::env::var(var) else {
+        return Ok(None);
+    };
+    let pid: u32 = pid.parse().context(var)?;
+    if pid != std::process::id() {
+        return Ok(None); // They were meant for someone else
+    }
+    let var = "LISTEN_FDS";
+    let n: i32 = std::env::var(var).context(var)?.parse().context(var)?;
+    ensure!(n == 1, "Expected one socket from systemd, got {n}");
+    // SAFETY: systemd passed us this fd, and nobody else is using it
+    let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+    // So that it doesn't leak into child processes
+    rustix::io::fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+    info!("Using the socket passed by systemd");
+    Ok(Some(TcpListener::from(fd)))
+}
+
+/// Feeds the watchdog and keeps the status line up to date.  Driven by the
+/// runloop, so that if the runloop gets stuck, systemd notices.
+pub struct Notifier {
+    /// How often to feed the watchdog, if it's enabled
+    watchdog_interval: Option<Duration>,
+    last_ping: Instant,
+    last_status: Instant,
+}
+
+impl Notifier {
+    pub fn new() -> Result<Notifier> {
+        let mut watchdog_interval = None;
+        let pid_matches = match std::env::var("WATCHDOG_PID") {
+            Ok(pid) => pid.parse() == Ok(std::process::id()),
+            Err(_) => true,
+        };
+        if let Ok(usec) = std::env::var("WATCHDOG_USEC")
+            && pid_matches
+        {
+            let usec: u64 = usec.parse().context("WATCHDOG_USEC")?;
+            // systemd recommends pinging at half the timeout
+            let interval = Duration::from_micros(usec) / 2;
+            info!("Feeding the systemd watchdog every {interval:?}");
+            watchdog_interval = Some(interval);
+        }
+        Ok(Notifier {
+            watchdog_interval,
+            last_ping: Instant::now(),
+            last_status: Instant::now(),
+        })
+    }
+
+    pub fn tick(&mut self, n_clients: usize) {
+        let mut msg = String::new();
+        if let Some(interval) = self.watchdog_interval
+            && self.last_ping.elapsed() >= interval
+        {
+            msg.push_str("WATCHDOG=1\n");
+            self.last_ping = Instant::now();
+        }
+        if self.last_status.elapsed() >= STATUS_INTERVAL {
+            let lag = Duration::from_micros(UPSTREAM_LAG_US.load(Ordering::Relaxed));
+            msg.push_str(&format!(
+                "STATUS={n_clients} clients, upstream lag {:.1}s\n",
+                lag.as_secs_f64()
+            ));
+            self.last_status = Instant::now();
+        }
+        if !msg.is_empty() {
+            notify(&msg);
+        }
+    }
+}
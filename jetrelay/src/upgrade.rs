@@ -0,0 +1,388 @@
+//! Hot upgrades
+//!
+//! On SIGUSR2, we hand everything over to a fresh copy of the jetrelay binary
+//! (ie. whatever's at the path we were started from), without disconnecting
+//! anyone:
+//!
+//! 1. We stop accepting connections, and let each client carry on until its
+//!    pipe is empty at a frame boundary.  Clients which don't get there within
+//!    `QUIESCE_TIMEOUT` are disconnected (they can come back with a cursor).
+//! 2. We stop writing to the data files, and start the new binary, giving it
+//!    one end of a Unix socket via `JETRELAY_UPGRADE_FD`.
+//! 3. We send it our listening sockets, followed by each client's socket, along
+//!    with its position in the stream and its config.  The fds go over
+//!    `SCM_RIGHTS`.
+//! 4. The new process takes over the clients, and tells us it's ready.  We
+//!    tell systemd its PID, and exit without closing any connections.
+//! 5. The new process loads `jetrelay.dat` the same way it would on any other
+//!    startup, and then starts serving the clients.
+//!
+//! The index isn't sent: the new process rebuilds it from `jetrelay.dat`, and
+//! the same goes for the collection and compressed files.  That's what every
+//! startup does anyway, so there's no second format to keep in sync with the
+//! in-memory structures, and since it happens after step 4, we aren't kept
+//! waiting.  We don't send the data file either, since the new process can
+//! open it by path.  If anything goes wrong before the new process is ready,
+//! we kill it and carry on as before.
+//!
+//! Each message is a length-prefixed line of text, with at most one fd
+//! attached to the length.
+
+use crate::handshake::{Acceptor, ClientConfig};
+use crate::upstream::{Timestamp, WRITE_LOCK};
+use crate::{Client, ClientId, Feed, Position};
+use anyhow::{Context, Result, bail, ensure};
+use rustix::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use rustix::io::FdFlags;
+use rustix::net::{
+    RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, SendAncillaryBuffer,
+    SendAncillaryMessage, SendFlags,
+};
+use std::collections::HashMap;
+use std::io::{IoSlice, IoSliceMut, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+const FD_VAR: &str = "JETRELAY_UPGRADE_FD";
+
+/// How long we wait for clients to reach a frame boundary
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The runloop is blocked during the handover, so this needs to be well within
+/// the systemd watchdog's timeout
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Env vars which systemd gave us, but which are wrong for the new process
+const STALE_VARS: [&str; 4] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES", "WATCHDOG_PID"];
+
+/// The old process's side of an upgrade
+#[derive(Debug)]
+pub struct Upgrade {
+    /// Clients which haven't reached a frame boundary by then get left behind
+    deadline: Instant,
+}
+
+impl Upgrade {
+    /// The caller should also stop accepting connections
+    pub fn start(clients: &mut HashMap<ClientId, Client>) -> Upgrade {
+        info!("Upgrading; waiting for clients to reach a frame boundary");
+        for client in clients.values_mut() {
+            client.frozen = true;
+        }
+        Upgrade {
+            deadline: Instant::now() + QUIESCE_TIMEOUT,
+        }
+    }
+
+    /// Whether it's time to call `hand_over()`
+    pub fn ready(&self, clients: &mut HashMap<ClientId, Client>, file_len: u64) -> bool {
+        Instant::now() >= self.deadline
+            || clients
+                .values_mut()
+                .all(|x| x.closing || can_hand_over(x, file_len))
+    }
+
+    /// Start the new process, and give it everything.  If this succeeds, the
+    /// new process is in charge and we should exit.  If it fails, the caller
+    /// should [`cancel`] the upgrade.
+    pub fn hand_over(
+        self,
+        clients: &mut HashMap<ClientId, Client>,
+        acceptor: &Acceptor,
+        metrics_listener: Option<&TcpListener>,
+        file_len: u64,
+    ) -> Result<()> {
+        // The new process reads the data files at startup, so they mustn't
+        // change from here on
+        let lock = WRITE_LOCK.lock().unwrap();
+        // We won't be feeding the watchdog until this is over
+        crate::systemd::keep_alive();
+
+        let (mut conn, theirs) = UnixStream::pair()?;
+        conn.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+        conn.set_write_timeout(Some(HANDOVER_TIMEOUT))?;
+        // Without CLOEXEC, so that the new process inherits it
+        let inheritable = rustix::io::dup(&theirs)?;
+        drop(theirs);
+        let exe = std::env::args_os().next().context("No argv[0]")?;
+        let mut cmd = Command::new(&exe);
+        cmd.env(FD_VAR, inheritable.as_raw_fd().to_string());
+        for var in STALE_VARS {
+            cmd.env_remove(var);
+        }
+        let mut child = cmd.spawn().with_context(|| format!("{exe:?}"))?;
+        drop(inheritable);
+        info!(pid = child.id(), "Started the new process");
+
+        let mut send_everything = || -> Result<usize> {
+            send(&mut conn, "listener", Some(acceptor.listener.as_fd()))?;
+            if let Some(listener) = metrics_listener {
+                send(&mut conn, "metrics_listener", Some(listener.as_fd()))?;
+            }
+            let mut n_sent = 0;
+            for (client_id, client) in clients.iter_mut() {
+                if client.closing || !can_hand_over(client, file_len) {
+                    continue;
+                }
+                let Some(msg) = describe(*client_id, client, file_len) else {
+                    continue;
+                };
+                send(&mut conn, &msg, Some(client.conn.as_fd()))?;
+                n_sent += 1;
+            }
+            send(&mut conn, "done", None)?;
+            let (msg, _) = recv(&mut conn).context("The new process didn't get ready")?;
+            ensure!(msg == "ready", "Unexpected message: {msg:?}");
+            Ok(n_sent)
+        };
+        let n_sent = match send_everything() {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        };
+
+        crate::systemd::main_pid(child.id());
+        let n_left = clients.len() - n_sent;
+        if n_left > 0 {
+            warn!("Disconnecting {n_left} clients which couldn't be handed over");
+        }
+        info!("Handed over {n_sent} clients; exiting");
+        for client in clients.values_mut() {
+            // They belong to the new process now, so we leave them alone
+            client.closing = true;
+        }
+        // The new process is writing to the data files now, so our copier
+        // must never get the lock back
+        std::mem::forget(lock);
+        Ok(())
+    }
+}
+
+/// Go back to normal after a failed upgrade
+pub fn cancel(clients: &mut HashMap<ClientId, Client>, acceptor: &mut Acceptor) {
+    for client in clients.values_mut() {
+        client.frozen = false;
+    }
+    acceptor.stopped = false;
+}
+
+/// Whether the client is somewhere the new process can pick it up from
+fn can_hand_over(client: &mut Client, file_len: u64) -> bool {
+    !client.send_in_flight
+        && client.bytes_in_pipe == 0
+        && client.outbox.is_empty()
+        && client.recv_buf.is_empty()
+        && client.pending_options.is_none()
+        && !client.skip_to_live
+        && client.position(file_len).is_some()
+}
+
+/// `None` if the client's config can't be represented
+fn describe(client_id: ClientId, client: &mut Client, file_len: u64) -> Option<String> {
+    let position = client.position(file_len)?;
+    let config = ClientConfig {
+        cursor: None,
+        options: client.options.clone(),
+        compression: client.compression,
+        require_hello: matches!(client.feed, Feed::Hello(_)),
+        slow_consumer: client.slow_consumer,
+    };
+    // The address might have come from a PROXY header, so we can't rely on
+    // the new process getting it from the socket
+    let peer_addr = match client.peer_addr {
+        Some(x) => x.to_string(),
+        None => "-".to_owned(),
+    };
+    Some(format!(
+        "client {client_id} {} {} {} {peer_addr} {}",
+        position.offset,
+        position.after.0,
+        client.bytes_sent,
+        config.to_query_params()?,
+    ))
+}
+
+/// The new process's side of an upgrade
+pub struct Takeover {
+    conn: UnixStream,
+    pub listener: TcpListener,
+    pub metrics_listener: Option<TcpListener>,
+    clients: Vec<Inherited>,
+}
+
+struct Inherited {
+    id: ClientId,
+    conn: TcpStream,
+    peer_addr: Option<SocketAddr>,
+    config: ClientConfig,
+    position: Position,
+    bytes_sent: u64,
+}
+
+impl Takeover {
+    /// Receives everything from the old process, if we were started by one
+    pub fn receive() -> Result<Option<Takeover>> {
+        let Ok(fd) = std::env::var(FD_VAR) else {
+            return Ok(None);
+        };
+        let fd: RawFd = fd.parse().context(FD_VAR)?;
+        // SAFETY: The old process passed us this fd, and nobody else is using
+        // it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // So that it doesn't leak into child processes
+        rustix::io::fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+        let mut conn = UnixStream::from(fd);
+        conn.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+        conn.set_write_timeout(Some(HANDOVER_TIMEOUT))?;
+        info!("Taking over from the previous process");
+
+        let mut listener = None;
+        let mut metrics_listener = None;
+        let mut clients = vec![];
+        loop {
+            let (msg, fd) = recv(&mut conn)?;
+            let (kind, args) = msg.split_once(' ').unwrap_or((&msg, ""));
+            match kind {
+                "listener" => listener = Some(TcpListener::from(fd.context("No fd")?)),
+                "metrics_listener" => {
+                    metrics_listener = Some(TcpListener::from(fd.context("No fd")?))
+                }
+                "client" => {
+                    let conn = TcpStream::from(fd.context("No fd")?);
+                    clients.push(parse_client(args, conn).context("Bad client message")?);
+                }
+                "done" => break,
+                x => bail!("Unexpected message: {x:?}"),
+            }
+        }
+        info!("Received {} clients", clients.len());
+        Ok(Some(Takeover {
+            conn,
+            listener: listener.context("The old process didn't send its listener")?,
+            metrics_listener,
+            clients,
+        }))
+    }
+
+    /// Set up the clients we were given, and tell the old process to go away
+    pub fn finish(
+        mut self,
+        clients: &mut HashMap<ClientId, Client>,
+        acceptor: &mut Acceptor,
+    ) -> Result<()> {
+        for x in self.clients {
+            let _g = info_span!("", client_id = x.id).entered();
+            info!("Took over client");
+            // These count towards the limits, but aren't subject to them
+            let permit = acceptor.limits.permit(x.peer_addr.map(|x| x.ip()));
+            let client =
+                Client::starting_at(x.conn, x.peer_addr, x.config, vec![], x.position, permit);
+            // eg. if we've been started with compression disabled.  The client
+            // will have to reconnect.
+            let mut client = match client {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Couldn't take over client: {e:#}");
+                    continue;
+                }
+            };
+            client.bytes_sent = x.bytes_sent;
+            acceptor.next_client_id = acceptor.next_client_id.max(x.id + 1);
+            clients.insert(x.id, client);
+        }
+        send(&mut self.conn, "ready", None)?;
+        Ok(())
+    }
+}
+
+fn parse_client(args: &str, conn: TcpStream) -> Result<Inherited> {
+    let mut args = args.splitn(6, ' ');
+    let mut next = || args.next().context("Too few fields");
+    Ok(Inherited {
+        id: next()?.parse()?,
+        position: Position {
+            offset: next()?.parse()?,
+            after: Timestamp(next()?.parse()?),
+        },
+        bytes_sent: next()?.parse()?,
+        peer_addr: match next()? {
+            "-" => None,
+            x => Some(x.parse()?),
+        },
+        config: ClientConfig::from_query_params(next()?)?,
+        conn,
+    })
+}
+
+fn send(conn: &mut UnixStream, msg: &str, fd: Option<BorrowedFd>) -> Result<()> {
+    let fds: Vec<BorrowedFd> = fd.into_iter().collect();
+    let mut space = [MaybeUninit::uninit(); rustix::cmsg_space!(ScmRights(1))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() {
+        control.push(SendAncillaryMessage::ScmRights(&fds));
+    }
+    let len = u32::try_from(msg.len())?.to_le_bytes();
+    let n = rustix::net::sendmsg(
+        &*conn,
+        &[IoSlice::new(&len)],
+        &mut control,
+        SendFlags::NOSIGNAL,
+    )?;
+    ensure!(n == len.len(), "Short write");
+    conn.write_all(msg.as_bytes())?;
+    Ok(())
+}
+
+fn recv(conn: &mut UnixStream) -> Result<(String, Option<OwnedFd>)> {
+    let mut len = [0; 4];
+    let mut space = [MaybeUninit::uninit(); rustix::cmsg_space!(ScmRights(1))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let flags = RecvFlags::CMSG_CLOEXEC;
+    let n = rustix::net::recvmsg(
+        &*conn,
+        &mut [IoSliceMut::new(&mut len)],
+        &mut control,
+        flags,
+    )?
+    .bytes;
+    let mut fd = None;
+    for msg in control.drain() {
+        if let RecvAncillaryMessage::ScmRights(mut fds) = msg {
+            fd = fds.next();
+        }
+    }
+    ensure!(n != 0, "The other process hung up");
+    conn.read_exact(&mut len[n..])?;
+    let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+    conn.read_exact(&mut buf)?;
+    Ok((String::from_utf8(buf)?, fd))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passing_fds() {
+        let (mut a, mut b) = UnixStream::pair().unwrap();
+        let (x, mut y) = UnixStream::pair().unwrap();
+        send(&mut a, "hello", Some(x.as_fd())).unwrap();
+        send(&mut a, "world", None).unwrap();
+        let (msg, fd) = recv(&mut b).unwrap();
+        assert_eq!(msg, "hello");
+        UnixStream::from(fd.unwrap()).write_all(b"!").unwrap();
+        let mut buf = [0];
+        y.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"!");
+        let (msg, fd) = recv(&mut b).unwrap();
+        assert_eq!(msg, "world");
+        assert!(fd.is_none());
+    }
+}
@@ -1,21 +1,40 @@
-use anyhow::{Context, Result, bail, ensure};
-use rustix::fs::FallocateFlags;
-use std::collections::BTreeMap;
+use crate::collections;
+use crate::collections::COLLECTIONS;
+use crate::compression::{FSST_FILE, FsstWriter, ZSTD_FILE, ZstdWriter};
+use crate::datafile::{DataFile, WATERMARK};
+use crate::dids::{DID_INDEX, FrameLoc};
+use crate::merge::identify;
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use rustix::fs::{FallocateFlags, SeekFrom};
+use rustix::io::Errno;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::prelude::*;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::*;
-use wsclient::{Frame, OpCode};
+use url::Url;
+use wsclient::{Frame, NeedMoreBytes, OpCode};
 
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Copy, Clone)]
+#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Copy, Clone, Default)]
 pub struct Timestamp(pub u64 /* epoch micros */);
 
+impl std::ops::Add<Duration> for Timestamp {
+    type Output = Timestamp;
+    fn add(self, rhs: Duration) -> Self::Output {
+        Timestamp(self.0 + rhs.as_micros() as u64)
+    }
+}
+
+/// Saturates, since clients can ask for `cursor=0`
 impl std::ops::Sub<Duration> for Timestamp {
     type Output = Timestamp;
     fn sub(self, rhs: Duration) -> Self::Output {
-        Timestamp(self.0 - rhs.as_micros() as u64)
+        Timestamp(self.0.saturating_sub(rhs.as_micros() as u64))
     }
 }
 
@@ -29,39 +48,604 @@ pub fn resolve_cursor(ts: Timestamp) -> Option<u64> {
     INDEX.lock().unwrap().range(ts..).next().map(|x| *x.1)
 }
 
-const MIN_RETENTION: Duration = Duration::from_secs(60);
-const MAX_RETENTION: Duration = Duration::from_secs(2 * 60);
+/// The timestamp of every frame in `jetrelay.dat`, keyed by offset: the
+/// inverse of `INDEX`
+pub static TIMESTAMPS: Mutex<BTreeMap<u64, Timestamp>> = Mutex::new(BTreeMap::new());
 
-pub fn copy_frames_to_file(
-    mut file: File,
-    file_len: Arc<AtomicU64>,
-    iter: impl Iterator<Item = std::io::Result<Frame>>,
-) -> Result<()> {
-    let _g = info_span!("upstream copier thread").entered();
-    info!("Copying data from upstream");
-    let mut first_timestamp = Timestamp(0);
-    for frame in iter {
-        let frame = match frame {
-            Ok(x) => x,
-            Err(e) => {
-                warn!("I/O error while reading from websocket: {e:#}");
+/// The inverse of `resolve_cursor()`: a timestamp which frames before
+/// `offset` are no later than, and frames from `offset` onwards are after.
+/// `offset` should be on a frame boundary.  Frames which share a timestamp
+/// can't be split, so this is only approximate in that case.
+pub fn timestamp_before(offset: u64) -> Timestamp {
+    let timestamps = TIMESTAMPS.lock().unwrap();
+    if let Some(ts) = timestamps.get(&offset) {
+        return *ts - Duration::from_micros(1);
+    }
+    match timestamps.range(..offset).next_back() {
+        Some((_, ts)) => *ts,
+        None => Timestamp(0),
+    }
+}
+
+/// Everything in `jetrelay.dat` before this offset has been dropped.  Clients
+/// who are still back there are dealt with by `crate::policy`.
+pub static LOW_WATER: AtomicU64 = AtomicU64::new(0);
+
+/// Bumped each time we publish new low-water marks
+pub static DROP_GENERATION: AtomicU64 = AtomicU64::new(0);
+/// The runloop sets this to the drop generation once none of its fills are
+/// reading from below the low-water marks.  Only then do we punch out the
+/// dropped data: a fill reads the file when it runs, not when it's submitted,
+/// so punching any sooner could send zeroes to a client.
+pub static PUNCH_SAFE: AtomicU64 = AtomicU64::new(0);
+
+/// The sizes of the frames in `jetrelay.dat`
+pub static FRAME_SIZES: Mutex<FrameSizes> = Mutex::new(FrameSizes::new());
+
+/// The size of every frame in a file, keyed by offset.  Unlike `INDEX`, this
+/// has an entry for every frame, even if several share a timestamp.
+#[derive(Debug, Default)]
+pub struct FrameSizes(BTreeMap<u64, u64>);
+
+impl FrameSizes {
+    pub const fn new() -> Self {
+        FrameSizes(BTreeMap::new())
+    }
+
+    /// Must be called _before_ the file length is updated, so that anyone who
+    /// sees the new length will also see the new entry
+    pub fn insert(&mut self, offset: u64, len: u64) {
+        self.0.insert(offset, len);
+    }
+
+    /// Forget about frames which start before `offset`
+    pub fn drop_old_data(&mut self, offset: u64) {
+        self.0 = self.0.split_off(&offset);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether `offset` is the start of a frame.  Offsets in data which has
+    /// already been dropped count too: there are no frames left to split.
+    pub fn is_boundary(&self, offset: u64) -> bool {
+        self.0.contains_key(&offset) || self.0.first_key_value().is_none_or(|x| offset < *x.0)
+    }
+
+    /// The end of the frame which contains `offset`
+    pub fn frame_end(&self, offset: u64) -> Option<u64> {
+        let (start, len) = self.0.range(..=offset).next_back()?;
+        Some(start + len)
+    }
+
+    /// Returns the next range of bytes to send to a client who doesn't want
+    /// frames with payloads bigger than `limit`.  If there are such frames at
+    /// the start of `start..end`, they're skipped; otherwise the range stops
+    /// short of the first one.  The returned range may be empty.
+    ///
+    /// `start` doesn't have to be on a frame boundary, so long as the frame
+    /// it's in the middle of is not too big.
+    pub fn sendable_range(&self, mut start: u64, end: u64, limit: u64) -> Range<u64> {
+        // We'll only be able to fit so much in the pipe anyway, so there's no
+        // point scanning any further
+        const MAX_SCAN: u64 = 1 << 20;
+        let end = end.min(start + MAX_SCAN);
+        for (&offset, &len) in self.0.range(start..end) {
+            if payload_len(len) <= limit {
                 continue;
             }
+            if offset == start {
+                trace!("Skipping a {len} byte frame");
+                start += len;
+            } else {
+                return start..offset;
+            }
+        }
+        start..end.max(start)
+    }
+}
+
+/// Work out the size of an unmasked frame's payload from the size of the
+/// whole frame
+pub fn payload_len(frame_len: u64) -> u64 {
+    match frame_len {
+        ..=127 => frame_len - 2,
+        128..=65539 => frame_len - 4,
+        _ => frame_len - 10,
+    }
+}
+
+/// How often to check how much disk space we're using
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// While this is set, the copier stops reading from upstream.  Set by the
+/// admin API.
+pub static INGEST_PAUSED: AtomicBool = AtomicBool::new(false);
+/// Drop old data now, as if we'd hit the age limit.  Set by the admin API.
+pub static SWEEP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Held by the copier while it's writing to the data files.  Whoever holds it
+/// can be sure they aren't changing.
+pub static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// How often to check whether ingest has been resumed
+pub const PAUSE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Everything the upstream copier writes to
+pub struct Sink {
+    pub file: File,
+    pub file_len: Arc<AtomicU64>,
+    pub collections: collections::Writer,
+    pub zstd: Option<ZstdWriter>,
+    pub fsst: Option<FsstWriter>,
+    pub retention: Retention,
+}
+
+/// How much data to keep.  We always keep `max_age` worth, but only drop the
+/// older data once there's another half of that, so that we're not punching
+/// holes all the time.  Once `max_bytes` is exceeded, we drop the oldest data
+/// until we're back down to half of it.
+pub struct Retention {
+    pub max_age: Duration,
+    /// The disk space used by all the data files together
+    pub max_bytes: Option<u64>,
+    /// The timestamp of the oldest data we have
+    first_timestamp: Timestamp,
+    last_size_check: Instant,
+    /// The drop generation whose data we haven't punched out yet
+    unpunched: Option<u64>,
+}
+
+impl Retention {
+    pub fn new(max_age: Duration, max_bytes: Option<u64>) -> Retention {
+        Retention {
+            max_age,
+            max_bytes,
+            first_timestamp: Timestamp(0),
+            last_size_check: Instant::now(),
+            unpunched: None,
+        }
+    }
+
+    /// If we're over either limit, returns the timestamp to drop data up to
+    fn cutoff(&mut self, now: Timestamp, file: &File, file_len: u64) -> Result<Option<Timestamp>> {
+        let forced = SWEEP_REQUESTED.swap(false, Ordering::Relaxed);
+        if forced {
+            info!("Sweeping old data");
+        }
+        let mut cutoff = None;
+        // There's nothing to drop if we don't have `max_age` worth yet, eg.
+        // when a sweep is forced before we've had any data
+        let max_age = self.max_age.as_micros() as u64;
+        if let Some(keep_from) = now.0.checked_sub(max_age)
+            && (forced || self.first_timestamp.0 + max_age / 2 < keep_from)
+        {
+            cutoff = Some(Timestamp(keep_from));
+        }
+        if let Some(max_bytes) = self.max_bytes
+            && (forced || self.last_size_check.elapsed() >= SIZE_CHECK_INTERVAL)
+        {
+            self.last_size_check = Instant::now();
+            let allocated = allocated_bytes(file)?;
+            if allocated > max_bytes {
+                debug!("Using {allocated} bytes of disk space; the limit is {max_bytes}");
+                cutoff = cutoff.max(byte_cutoff(allocated, max_bytes / 2, file_len));
+            }
+        }
+        Ok(cutoff)
+    }
+}
+
+/// The disk space used by `jetrelay.dat` and all the files alongside it
+pub fn allocated_bytes(file: &File) -> Result<u64> {
+    let size = |x: &File| -> Result<u64> { Ok(rustix::fs::fstat(x)?.st_blocks as u64 * 512) };
+    let mut total = size(file)?;
+    for x in COLLECTIONS.lock().unwrap().iter() {
+        total += size(&x.file)?;
+    }
+    for x in ZSTD_FILE.get().into_iter().chain(FSST_FILE.get()) {
+        total += size(&x.file)?;
+    }
+    Ok(total)
+}
+
+/// The timestamp to drop data up to in order to get from `allocated` bytes
+/// down to `target`.  We assume that the other files shrink in proportion to
+/// `jetrelay.dat`.
+fn byte_cutoff(allocated: u64, target: u64, file_len: u64) -> Option<Timestamp> {
+    let index = INDEX.lock().unwrap();
+    let (_, first_offset) = index.first_key_value()?;
+    let retained = file_len - first_offset;
+    let n_drop = retained as f64 * (1. - target as f64 / allocated as f64);
+    let offset = first_offset + n_drop as u64;
+    index
+        .iter()
+        .find(|x| *x.1 >= offset)
+        .or(index.last_key_value())
+        .map(|x| *x.0)
+}
+
+/// How long to wait before reconnecting to upstream.  This doubles each time
+/// we fail to get anything out of any of the upstreams.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// While we're on a lower-priority upstream, we check this often whether a
+/// higher-priority one is back
+const FAILBACK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many upstream switches we remember
+const MAX_SWITCH_HISTORY: usize = 20;
+
+/// How often to log the upstream lag
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// When we reconnect, we ask upstream to start this far before the last event
+/// we saw, to make sure there's no gap.  Anything we already have gets
+/// dropped.
+const CURSOR_OVERLAP: Duration = Duration::from_secs(1);
+
+/// The index (into `UPSTREAM_URL`) of the upstream we're reading from
+pub static ACTIVE_UPSTREAM: AtomicUsize = AtomicUsize::new(0);
+/// The number of times we've switched from one upstream to another
+pub static UPSTREAM_SWITCHES: AtomicU64 = AtomicU64::new(0);
+/// The last few of those switches, oldest first
+pub static SWITCH_HISTORY: Mutex<VecDeque<Switch>> = Mutex::new(VecDeque::new());
+/// How far behind the wall clock the last event we wrote was, in micros
+pub static UPSTREAM_LAG_US: AtomicU64 = AtomicU64::new(0);
+
+/// Decides when to give up on an upstream connection
+#[derive(Debug, Clone, Copy)]
+pub struct Watchdog {
+    /// If an upstream goes quiet for this long, we assume it's stalled
+    pub stall_timeout: Duration,
+    /// If an upstream falls this far behind the wall clock, we reconnect
+    pub max_lag: Duration,
+}
+
+impl Watchdog {
+    /// A new connection gets `max_lag` to catch up before we start checking,
+    /// since it's probably replaying from a cursor
+    pub fn is_lagging(&self, connected_at: Instant, lag: Duration) -> bool {
+        connected_at.elapsed() > self.max_lag && lag > self.max_lag
+    }
+}
+
+/// Runs forever.  We stick with one upstream until it disconnects, stalls, or
+/// falls behind, and then move on to the next one, resuming by cursor.  While
+/// we're on a lower-priority upstream, we keep checking whether the ones before
+/// it are back, and switch back as soon as one is.
+///
+/// While ingest is paused we hang up, rather than leave upstream waiting for
+/// us, and then resume by cursor as usual.
+pub fn copy_frames_to_file(mut sink: Sink, urls: Vec<Url>, watchdog: Watchdog) {
+    let _g = info_span!("upstream copier thread").entered();
+    let mut last_report = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut n_failures = 0;
+    let mut active = 0;
+    loop {
+        wait_while_paused(&mut sink);
+        let url = with_cursor(&urls[active]);
+        info!("Connecting to upstream {active}: {url}");
+        let mut fail_back = None;
+        let mut paused = false;
+        let reason =
+            match wsclient::connect_websocket_with_timeout(&url, Some(watchdog.stall_timeout)) {
+                Ok(iter) => {
+                    info!("Copying data from upstream");
+                    crate::systemd::ready();
+                    let connected_at = Instant::now();
+                    let mut replay = Replay::start(&sink);
+                    let mut probe = Probe::new(&urls[..active], watchdog.stall_timeout);
+                    let mut reason = "disconnected";
+                    for frame in iter {
+                        let frame = match frame {
+                            Ok(x) => x,
+                            Err(e) => {
+                                warn!("I/O error while reading from websocket: {e:#}");
+                                reason = "I/O error";
+                                break;
+                            }
+                        };
+                        // The connection is working, so the next failure gets a
+                        // fresh start
+                        n_failures = 0;
+                        backoff = INITIAL_BACKOFF;
+                        if INGEST_PAUSED.load(Ordering::Relaxed) {
+                            // We'll get this frame again when we resume
+                            info!("Disconnecting from upstream while ingest is paused");
+                            paused = true;
+                            break;
+                        }
+                        if let Err(e) = handle_frame(&mut sink, frame, &mut replay) {
+                            warn!("Bad frame: {e:#}");
+                        }
+                        let lag = record_lag(&mut last_report);
+                        if watchdog.is_lagging(connected_at, lag) {
+                            warn!("Upstream {active} is {lag:?} behind");
+                            reason = "lagging";
+                            break;
+                        }
+                        if let Some(i) = probe.poll() {
+                            info!("Upstream {i} is back");
+                            fail_back = Some(i);
+                            reason = "failing back";
+                            break;
+                        }
+                    }
+                    if fail_back.is_none() && !paused {
+                        warn!("Lost the connection to upstream {active}");
+                    }
+                    reason
+                }
+                Err(e) => {
+                    warn!("Couldn't connect to upstream {active}: {e:#}");
+                    "couldn't connect"
+                }
+            };
+        if paused {
+            continue; // Not a failure, so we stay where we are
+        }
+        let next = match fail_back {
+            Some(i) => i,
+            None => {
+                n_failures += 1;
+                if n_failures >= urls.len() {
+                    // We've tried everything
+                    info!("Reconnecting in {backoff:?}");
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                (active + 1) % urls.len()
+            }
         };
-        match handle_frame(&mut first_timestamp, &mut file, &file_len, frame) {
-            Ok(()) => (),
-            Err(e) => warn!("Bad frame: {e:#}"),
+        if next != active {
+            record_switch(active, next, reason);
+            active = next;
         }
     }
-    Ok(())
 }
 
-fn handle_frame(
-    first_timestamp: &mut Timestamp,
-    file: &mut File,
-    file_len: &AtomicU64,
-    frame: Frame,
-) -> anyhow::Result<()> {
+/// Checks in the background, every `FAILBACK_INTERVAL`, whether any of the
+/// higher-priority upstreams are working again
+struct Probe {
+    urls: Vec<Url>,
+    timeout: Duration,
+    last_started: Instant,
+    in_flight: Option<JoinHandle<Option<usize>>>,
+}
+
+impl Probe {
+    /// `urls` are the upstreams to check, in priority order
+    fn new(urls: &[Url], timeout: Duration) -> Probe {
+        Probe {
+            urls: urls.to_vec(),
+            timeout,
+            last_started: Instant::now(),
+            in_flight: None,
+        }
+    }
+
+    /// Returns the first upstream which sent us something, once we know
+    fn poll(&mut self) -> Option<usize> {
+        if let Some(x) = &self.in_flight
+            && x.is_finished()
+        {
+            return self.in_flight.take().unwrap().join().ok().flatten();
+        }
+        if self.urls.is_empty()
+            || self.in_flight.is_some()
+            || self.last_started.elapsed() < FAILBACK_INTERVAL
+        {
+            return None;
+        }
+        let urls = self.urls.clone();
+        let timeout = self.timeout;
+        let probe = move || {
+            urls.iter().position(|url| {
+                let mut iter = match wsclient::connect_websocket_with_timeout(url, Some(timeout)) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        debug!("{url} still isn't working: {e:#}");
+                        return false;
+                    }
+                };
+                iter.next().is_some_and(|x| x.is_ok())
+            })
+        };
+        self.in_flight = std::thread::Builder::new()
+            .name("upstream_probe".to_owned())
+            .spawn(probe)
+            .inspect_err(|e| warn!("Couldn't start the probe: {e}"))
+            .ok();
+        self.last_started = Instant::now();
+        None
+    }
+}
+
+/// A change of upstream, for the status page
+pub struct Switch {
+    pub time: Timestamp,
+    pub from: usize,
+    pub to: usize,
+    pub reason: &'static str,
+}
+
+fn record_switch(from: usize, to: usize, reason: &'static str) {
+    ACTIVE_UPSTREAM.store(to, Ordering::Relaxed);
+    let n = UPSTREAM_SWITCHES.fetch_add(1, Ordering::Relaxed) + 1;
+    info!("Switching to upstream {to} (switch #{n}): {reason}");
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut history = SWITCH_HISTORY.lock().unwrap();
+    if history.len() >= MAX_SWITCH_HISTORY {
+        history.pop_front();
+    }
+    history.push_back(Switch {
+        time: Timestamp(time.as_micros() as u64),
+        from,
+        to,
+        reason,
+    });
+}
+
+/// Runs forever, writing the merged stream from `crate::merge`
+pub fn merge_frames_to_file(mut sink: Sink, frames: impl Iterator<Item = Frame>) {
+    let _g = info_span!("upstream copier thread").entered();
+    info!("Copying merged data from upstream");
+    let mut last_report = Instant::now();
+    for frame in frames {
+        wait_while_paused(&mut sink);
+        // The merger has already dropped the events we have.  Comparing
+        // timestamps with the watermark would drop the late ones which it has
+        // moved up to the watermark, which are the ones we're here to recover.
+        if let Err(e) = handle_frame(&mut sink, frame, &mut None) {
+            warn!("Bad frame: {e:#}");
+        }
+        record_lag(&mut last_report);
+    }
+}
+
+/// Blocks while ingest is paused.  Sweeps still happen in the meantime.
+fn wait_while_paused(sink: &mut Sink) {
+    if !INGEST_PAUSED.load(Ordering::Relaxed) {
+        return;
+    }
+    info!("Ingest is paused");
+    while INGEST_PAUSED.load(Ordering::Relaxed) {
+        if SWEEP_REQUESTED.load(Ordering::Relaxed) || sink.retention.unpunched.is_some() {
+            let _lock = WRITE_LOCK.lock().unwrap();
+            let now = Timestamp(WATERMARK.load(Ordering::Relaxed));
+            let file_len = sink.file_len.load(Ordering::Relaxed);
+            if let Err(e) = apply_retention(sink, now, file_len) {
+                warn!("Couldn't drop old data: {e:#}");
+            }
+        }
+        std::thread::sleep(PAUSE_POLL_INTERVAL);
+    }
+    info!("Ingest has resumed");
+}
+
+/// Update the lag metric, and log it every so often
+fn record_lag(last_report: &mut Instant) -> Duration {
+    let lag = lag_behind(Timestamp(WATERMARK.load(Ordering::Relaxed)));
+    UPSTREAM_LAG_US.store(lag.as_micros() as u64, Ordering::Relaxed);
+    if last_report.elapsed() >= LAG_REPORT_INTERVAL {
+        info!("Upstream lag: {lag:?}");
+        *last_report = Instant::now();
+    }
+    lag
+}
+
+/// How far behind the wall clock `ts` is
+pub fn lag_behind(ts: Timestamp) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.saturating_sub(Duration::from_micros(ts.0))
+}
+
+/// Resume from just before the last event we have, if there is one
+pub fn with_cursor(url: &Url) -> Url {
+    let watermark = WATERMARK.load(Ordering::Acquire);
+    if watermark == 0 {
+        return url.clone();
+    }
+    let cursor = Timestamp(watermark) - CURSOR_OVERLAP;
+    let mut url = url.clone();
+    let params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| k != "cursor")
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(params)
+        .append_pair("cursor", &cursor.0.to_string());
+    url
+}
+
+/// The identities of the events we have with timestamps from `since` onwards
+fn ids_since(sink: &Sink, since: Timestamp) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    // The newest frames are the ones at the end
+    let timestamps = TIMESTAMPS.lock().unwrap();
+    let sizes = FRAME_SIZES.lock().unwrap();
+    for (offset, _) in timestamps.iter().rev().take_while(|x| *x.1 >= since) {
+        let Some(len) = sizes.0.get(offset) else {
+            continue;
+        };
+        let mut buf = vec![0; *len as usize];
+        if let Err(e) = sink.file.read_exact_at(&mut buf, *offset) {
+            warn!("Couldn't read the frame at {offset}: {e}");
+            continue;
+        }
+        let id = Frame::from_slice(&buf)
+            .ok()
+            .and_then(|x| std::str::from_utf8(x.payload()).ok().and_then(identify));
+        if let Some((_, Some(id))) = id {
+            ids.insert(id);
+        }
+    }
+    ids
+}
+
+/// The identities of the events which upstream will send us again when we
+/// connect with [`with_cursor`]
+pub fn cursor_overlap(sink: &Sink) -> HashSet<String> {
+    let watermark = WATERMARK.load(Ordering::Acquire);
+    if watermark == 0 {
+        return HashSet::new();
+    }
+    ids_since(sink, Timestamp(watermark) - CURSOR_OVERLAP)
+}
+
+/// When we reconnect with a cursor, upstream replays events we already have.
+/// We drop those, until upstream gets past the newest event we had.
+struct Replay {
+    /// The timestamp of the newest event we had when we reconnected
+    until: Timestamp,
+    /// The events we had with that timestamp.  Several events can share a
+    /// timestamp, so the timestamp alone doesn't tell us whether we have one.
+    ids: HashSet<String>,
+}
+
+impl Replay {
+    /// Returns `None` if we don't have any data yet
+    fn start(sink: &Sink) -> Option<Replay> {
+        let until = Timestamp(WATERMARK.load(Ordering::Acquire));
+        if until.0 == 0 {
+            return None;
+        }
+        let ids = ids_since(sink, until);
+        Some(Replay { until, ids })
+    }
+
+    /// Whether we already have this event
+    fn is_repeat(&self, timestamp: Timestamp, payload: &str) -> bool {
+        match timestamp.cmp(&self.until) {
+            std::cmp::Ordering::Less => true,
+            std::cmp::Ordering::Equal => {
+                identify(payload).is_some_and(|(_, id)| id.is_some_and(|x| self.ids.contains(&x)))
+            }
+            std::cmp::Ordering::Greater => false,
+        }
+    }
+}
+
+fn handle_frame(sink: &mut Sink, frame: Frame, replay: &mut Option<Replay>) -> anyhow::Result<()> {
+    let _lock = WRITE_LOCK.lock().unwrap();
+    let Sink {
+        file,
+        file_len,
+        collections,
+        zstd,
+        fsst,
+        ..
+    } = sink;
     match frame.opcode() {
         OpCode::Text => (),            // Expected
         OpCode::Ping => return Ok(()), // Ignore
@@ -69,59 +653,339 @@ fn handle_frame(
         OpCode::Binary => bail!("Binary frame: {frame:?}"),
         x => bail!("Unexpected opcode: {x:?}"),
     }
-    let timestamp = parse_frame(&frame).with_context(|| format!("{:?}", frame.bytes))?;
+    let info = parse_frame(&frame).with_context(|| format!("{:?}", frame.bytes))?;
+    let timestamp = info.timestamp;
+    if let Some(r) = replay {
+        if r.is_repeat(timestamp, std::str::from_utf8(frame.payload())?) {
+            trace!("Skipping a repeated event at ts={timestamp:?}");
+            return Ok(());
+        }
+        if timestamp > r.until {
+            debug!("Caught up with the events we already had");
+            *replay = None;
+        }
+    }
+
+    let collection_file =
+        write_derived_files(collections, zstd.as_mut(), fsst.as_ref(), &info, &frame);
 
     file.write_all(&frame.bytes)?;
     file.flush()?;
     let n = frame.bytes.len() as u64;
     trace!("Wrote {n} bytes");
-    let offset = file_len.fetch_add(n, Ordering::Release);
+    crate::metrics::UPSTREAM_FRAMES.fetch_add(1, Ordering::Relaxed);
+    crate::metrics::UPSTREAM_BYTES.fetch_add(n, Ordering::Relaxed);
+    // We're the only writer, so we can safely read the length now and update
+    // it later
+    let offset = file_len.load(Ordering::Relaxed);
+    index_frame(file_len, offset, n, &info, collection_file);
 
-    INDEX.lock().unwrap().insert(timestamp, offset);
+    apply_retention(sink, timestamp, offset + n)?;
+
+    crate::io::wake_runloop();
+    Ok(())
+}
 
-    // If retention is over the max, drop until it's at the min
-    if *first_timestamp < timestamp - MAX_RETENTION {
-        drop_old_data(file, timestamp - MIN_RETENTION)?;
-        *first_timestamp = INDEX
+/// Drop old data, if we're over either of the limits.  The data is dropped
+/// from the indices straight away, but only punched out of the files once the
+/// runloop has stopped reading it; see `PUNCH_SAFE`.
+fn apply_retention(sink: &mut Sink, now: Timestamp, file_len: u64) -> Result<()> {
+    let Sink {
+        file,
+        collections,
+        zstd,
+        fsst,
+        retention,
+        ..
+    } = sink;
+    if let Some(generation) = retention.unpunched {
+        // We don't drop any more data in the meantime, so the low-water marks
+        // are still the ones from this generation
+        if PUNCH_SAFE.load(Ordering::Acquire) < generation {
+            return Ok(());
+        }
+        punch_hole(file)?;
+        collections.punch_holes()?;
+        if let Some(zstd) = zstd {
+            zstd.punch_hole()?;
+        }
+        if let Some(fsst) = fsst {
+            fsst.punch_hole()?;
+        }
+        retention.unpunched = None;
+    }
+    if let Some(cutoff) = retention.cutoff(now, file, file_len)? {
+        drop_old_data(cutoff);
+        collections.drop_old_data(cutoff);
+        if let Some(zstd) = zstd {
+            zstd.drop_old_data(cutoff);
+        }
+        if let Some(fsst) = fsst {
+            fsst.drop_old_data(cutoff);
+        }
+        let generation = DROP_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+        retention.unpunched = Some(generation);
+        crate::io::wake_runloop();
+        retention.first_timestamp = INDEX
             .lock()
             .unwrap()
             .first_key_value()
             .map_or(Timestamp(0), |x| *x.0);
-        debug!("Dropped some data, new first_timestamp={first_timestamp:?}");
+        debug!(
+            "Dropped some data, new first_timestamp={:?}",
+            retention.first_timestamp
+        );
+    }
+    Ok(())
+}
+
+/// Write a frame to the collection file and the compressed files.  Returns the
+/// collection file it went into.
+fn write_derived_files(
+    collections: &mut collections::Writer,
+    zstd: Option<&mut ZstdWriter>,
+    fsst: Option<&FsstWriter>,
+    info: &EventInfo,
+    frame: &Frame<impl AsRef<[u8]>>,
+) -> Option<Arc<DataFile>> {
+    let collection_file = collections
+        .write(
+            info.collection.as_deref(),
+            info.timestamp,
+            frame.bytes.as_ref(),
+        )
+        .inspect_err(|e| warn!("Couldn't write to the collection file: {e:#}"))
+        .ok();
+
+    if let Some(zstd) = zstd
+        && let Err(e) = zstd.write(info.timestamp, frame)
+    {
+        warn!("Couldn't write to the zstd file: {e:#}");
+    }
+    if let Some(fsst) = fsst
+        && let Err(e) = fsst.write(info.timestamp, frame)
+    {
+        warn!("Couldn't write to the FSST file: {e:#}");
+    }
+    collection_file
+}
+
+/// Make a frame which has been written to `jetrelay.dat` at `offset` visible
+/// to clients
+fn index_frame(
+    file_len: &AtomicU64,
+    offset: u64,
+    n: u64,
+    info: &EventInfo,
+    collection: Option<Arc<DataFile>>,
+) {
+    let loc = FrameLoc {
+        offset,
+        len: n,
+        collection,
+    };
+    DID_INDEX.lock().unwrap().push(&info.did, loc);
+    FRAME_SIZES.lock().unwrap().insert(offset, n);
+    file_len.store(offset + n, Ordering::Release);
+
+    INDEX.lock().unwrap().insert(info.timestamp, offset);
+    TIMESTAMPS.lock().unwrap().insert(offset, info.timestamp);
+    WATERMARK.store(info.timestamp.0, Ordering::Release);
+}
+
+/// Whether `buf` starts like the frames we write: unmasked text frames, with no
+/// reserved bits set
+fn looks_like_frame(buf: &[u8]) -> bool {
+    buf[0] & 0b0111_1111 == OpCode::Text.to_raw() && buf[1] & 0b1000_0000 == 0
+}
+
+/// There's a bad frame at `offset`.  That's fine if it's just what a crash left
+/// at the end of the file, ie. there's nothing but zeroes from `from` onwards.
+/// Otherwise the file is corrupt.
+fn check_torn_tail(file: &File, offset: u64, from: u64, size: u64, problem: &str) -> Result<()> {
+    let mut buf = vec![0; 1 << 20];
+    let mut pos = from;
+    while pos < size {
+        let n = (size - pos).min(buf.len() as u64) as usize;
+        file.read_exact_at(&mut buf[..n], pos)?;
+        ensure!(
+            buf[..n].iter().all(|x| *x == 0),
+            "jetrelay.dat is corrupt at offset {offset} ({problem}).  Move it out of \
+             the way to start afresh.",
+        );
+        pos += n as u64;
+    }
+    warn!("Bad frame at offset {offset} ({problem}); assuming it was cut short by a crash");
+    Ok(())
+}
+
+/// How often to tell systemd we're still alive while loading
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pick up where a previous run left off.  We scan the frames which are still
+/// in `jetrelay.dat`, rebuilding the indices and re-writing the derived files
+/// as we go.  The start of the file has probably been punched out, and the
+/// end may contain a partially-written frame (or zeroes, after a crash), which
+/// we truncate.  A bad frame anywhere else means the file is corrupt, and we
+/// fail rather than throw away everything after it.
+pub fn load_existing_data(sink: &mut Sink) -> Result<()> {
+    let Sink {
+        file,
+        file_len,
+        collections,
+        zstd,
+        fsst,
+        retention,
+    } = sink;
+    const CHUNK_SIZE: usize = 1 << 20;
+    let size = file.metadata()?.len();
+    // Skip the holes.  This only gets us to the start of a block, so there may
+    // be some zeroes left before the first frame.
+    let start = match rustix::fs::seek(&*file, SeekFrom::Data(0)) {
+        Ok(x) => x,
+        Err(Errno::NXIO) => size, // There's no data at all
+        Err(e) => return Err(e.into()),
+    };
+    let mut buf = Vec::new();
+    // The file offset of `buf[0]`
+    let mut buf_offset = start;
+    let mut consumed = 0;
+    let mut first_offset = None;
+    let mut n_frames = 0;
+    let mut last_keep_alive = Instant::now();
+    loop {
+        if first_offset.is_none() {
+            // A frame never starts with a zero byte
+            consumed += buf[consumed..].iter().take_while(|x| **x == 0).count();
+        }
+        let offset = buf_offset + consumed as u64;
+        let rest = &buf[consumed..];
+        if rest.len() >= 2 && !looks_like_frame(rest) {
+            check_torn_tail(file, offset, offset, size, "Bad frame header")?;
+            break;
+        }
+        let frame = match Frame::from_slice(rest) {
+            Ok(x) => x,
+            Err(NeedMoreBytes(n)) => {
+                if rest.len() + n > wsclient::MAX_FRAME_SIZE {
+                    check_torn_tail(file, offset, offset, size, "Frame is too big")?;
+                    break;
+                }
+                buf.drain(..consumed);
+                buf_offset += consumed as u64;
+                consumed = 0;
+                let old_len = buf.len();
+                buf.resize(old_len + CHUNK_SIZE, 0);
+                let n = file.read_at(&mut buf[old_len..], buf_offset + old_len as u64)?;
+                buf.truncate(old_len + n);
+                if last_keep_alive.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    crate::systemd::keep_alive();
+                    last_keep_alive = Instant::now();
+                }
+                if n == 0 {
+                    break;
+                }
+                continue;
+            }
+        };
+        let info = match frame.opcode() {
+            OpCode::Text => parse_frame(&frame),
+            x => Err(anyhow!("Unexpected opcode: {x:?}")),
+        };
+        let info = match info {
+            Ok(x) => x,
+            Err(e) => {
+                // The frame may have been cut short, leaving zeroes where the
+                // rest of its payload should be
+                let end = offset + frame.bytes.len() as u64;
+                check_torn_tail(file, offset, end, size, &format!("{e:#}"))?;
+                break;
+            }
+        };
+        let collection_file =
+            write_derived_files(collections, zstd.as_mut(), fsst.as_ref(), &info, &frame);
+        let n = frame.bytes.len() as u64;
+        index_frame(file_len, offset, n, &info, collection_file);
+        first_offset.get_or_insert(offset);
+        n_frames += 1;
+        consumed += n as usize;
     }
 
-    // We could wake up the io_uring here... but we don't bother
+    let end = buf_offset + consumed as u64;
+    if end < size {
+        warn!("Truncating {} bytes from the end of the file", size - end);
+        file.set_len(end)?;
+    }
+    file_len.store(end, Ordering::Release);
+    if let Some((ts, _)) = INDEX.lock().unwrap().first_key_value() {
+        retention.first_timestamp = *ts;
+    }
+    LOW_WATER.store(first_offset.unwrap_or(end), Ordering::Release);
+    if let Some(first_offset) = first_offset {
+        info!(
+            "Loaded {n_frames} frames ({} bytes) from a previous run",
+            end - first_offset
+        );
+    }
     Ok(())
 }
 
-fn parse_frame(frame: &Frame) -> anyhow::Result<Timestamp> {
+/// The parts of an event which we need for indexing
+struct EventInfo {
+    timestamp: Timestamp,
+    did: String,
+    /// Only for commits
+    collection: Option<String>,
+}
+
+fn parse_frame(frame: &Frame<impl AsRef<[u8]>>) -> anyhow::Result<EventInfo> {
     ensure!(frame.reserved_bits() == 0, "Non-zero reserved bits");
     ensure!(frame.mask().is_none(), "Frame is masked");
     let payload = std::str::from_utf8(frame.payload())?;
     let timestamp = gjson::get(payload, "time_us");
     ensure!(timestamp.kind() == gjson::Kind::Number);
     let timestamp = Timestamp(timestamp.u64());
-    Ok(timestamp)
+    let did = gjson::get(payload, "did");
+    ensure!(did.kind() == gjson::Kind::String);
+    let did = did.str().to_owned();
+    let collection = match gjson::get(payload, "kind").str() {
+        "commit" => {
+            let collection = gjson::get(payload, "commit.collection");
+            ensure!(collection.kind() == gjson::Kind::String);
+            Some(collection.str().to_owned())
+        }
+        _ => None,
+    };
+    Ok(EventInfo {
+        timestamp,
+        did,
+        collection,
+    })
 }
 
-fn drop_old_data(file: &File, ts: Timestamp) -> anyhow::Result<()> {
-    static LAST_DROP_OFFSET: AtomicU64 = AtomicU64::new(0);
-
+/// Drop the data before `ts` from the indices, and publish the new low-water
+/// mark.  The data stays in the file until `punch_hole`.
+fn drop_old_data(ts: Timestamp) {
     let mut index = INDEX.lock().unwrap();
     let mut x = index.split_off(&ts);
     // split_off() returns everything after `ts`, but we want it the other way round
     std::mem::swap(&mut x, &mut *index);
     std::mem::drop(index);
 
-    if let Some((_, offset)) = x.last_key_value() {
+    if let (Some((first_ts, first_offset)), Some((_, offset))) =
+        (x.first_key_value(), x.last_key_value())
+    {
         debug!("Dropping data up to ts={ts:?}, offset={offset}");
-        let flags = FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE;
-        rustix::fs::fallocate(file, flags, 0, *offset)?;
+        LOW_WATER.store(*offset, Ordering::Release);
+        DID_INDEX.lock().unwrap().drop_old_data(*offset);
+        let mut timestamps = TIMESTAMPS.lock().unwrap();
+        *timestamps = timestamps.split_off(offset);
+        drop(timestamps);
+        FRAME_SIZES.lock().unwrap().drop_old_data(*offset);
 
         let n_dropped = x.len();
-        let duration = MAX_RETENTION - MIN_RETENTION; // approximately
-        let last_drop_offset = LAST_DROP_OFFSET.swap(*offset, Ordering::AcqRel);
-        let n_bytes = *offset - last_drop_offset;
+        let duration = Duration::from_micros(ts.0 - first_ts.0);
+        let n_bytes = *offset - *first_offset;
         info!("Over the last {duration:?} we recorded {n_dropped} msgs taking {n_bytes} bytes");
         info!(
             "Rate: {:.0} msgs/s, {:.1} KiB/s",
@@ -131,5 +995,107 @@ fn drop_old_data(file: &File, ts: Timestamp) -> anyhow::Result<()> {
     } else {
         warn!("Tried to drop up to ts={ts:?}, but there's no data that old");
     }
+}
+
+/// Deallocate the part of `jetrelay.dat` before the low-water mark
+fn punch_hole(file: &File) -> Result<()> {
+    let offset = LOW_WATER.load(Ordering::Acquire);
+    if offset > 0 {
+        let flags = FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE;
+        rustix::fs::fallocate(file, flags, 0, offset)?;
+        crate::metrics::HOLE_PUNCHES.fetch_add(1, Ordering::Relaxed);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip_big_frames() {
+        let mut sizes = FrameSizes::new();
+        let mut offset = 0;
+        for len in [10, 10, 200, 10, 300, 400, 10] {
+            sizes.insert(offset, len);
+            offset += len;
+        }
+        assert_eq!(sizes.sendable_range(0, offset, 100), 0..20);
+        assert_eq!(sizes.sendable_range(5, offset, 100), 5..20);
+        assert_eq!(sizes.sendable_range(20, offset, 100), 220..230);
+        assert_eq!(sizes.sendable_range(230, offset, 100), 930..940);
+        assert_eq!(sizes.sendable_range(230, 930, 100), 930..930);
+        assert_eq!(sizes.sendable_range(0, offset, 1000), 0..940);
+    }
+
+    #[test]
+    fn replay() {
+        let commit = |ts: u64, rkey: &str| {
+            format!(
+                r#"{{"did":"did:plc:a","time_us":{ts},"kind":"commit","commit":{{"rev":"3l","operation":"create","collection":"app.bsky.feed.post","rkey":"{rkey}"}}}}"#
+            )
+        };
+        let (_, id) = identify(&commit(1000, "a")).unwrap();
+        let replay = Replay {
+            until: Timestamp(1000),
+            ids: HashSet::from([id.unwrap()]),
+        };
+        assert!(replay.is_repeat(Timestamp(999), &commit(999, "z")));
+        assert!(replay.is_repeat(Timestamp(1000), &commit(1000, "a")));
+        // Shares a timestamp with "a", but we don't have it
+        assert!(!replay.is_repeat(Timestamp(1000), &commit(1000, "b")));
+        assert!(!replay.is_repeat(Timestamp(1001), &commit(1001, "c")));
+    }
+
+    #[test]
+    fn writing_frames() {
+        let dir = std::env::temp_dir().join(format!("jetrelay-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sink = Sink {
+            file: File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(dir.join("jetrelay.dat"))
+                .unwrap(),
+            file_len: Arc::new(AtomicU64::new(0)),
+            collections: collections::Writer::new(dir.clone()).unwrap(),
+            zstd: None,
+            fsst: None,
+            retention: Retention::new(Duration::from_secs(3600), None),
+        };
+        let t0 = 1_700_000_000_000_000;
+        let commit = |ts: u64, rkey: &str| {
+            let json = format!(
+                r#"{{"did":"did:plc:a","time_us":{ts},"kind":"commit","commit":{{"rev":"3l","operation":"create","collection":"app.bsky.feed.post","rkey":"{rkey}"}}}}"#
+            );
+            Frame::new(OpCode::Text, json.as_bytes())
+        };
+        let n_frames = || TIMESTAMPS.lock().unwrap().len();
+
+        // Merged frames aren't checked against the watermark, since the merger
+        // moves late events up to it
+        handle_frame(&mut sink, commit(t0, "a"), &mut None).unwrap();
+        handle_frame(&mut sink, commit(t0 + 5, "c"), &mut None).unwrap();
+        handle_frame(&mut sink, commit(t0 + 10, "b"), &mut None).unwrap();
+        handle_frame(&mut sink, commit(t0 + 10, "f"), &mut None).unwrap();
+        assert_eq!(n_frames(), 4);
+        assert_eq!(WATERMARK.load(Ordering::Acquire), t0 + 10);
+        assert_eq!(cursor_overlap(&sink).len(), 4);
+
+        // After reconnecting to a single upstream, we skip what it replays
+        let mut replay = Replay::start(&sink);
+        handle_frame(&mut sink, commit(t0 + 5, "c"), &mut replay).unwrap();
+        handle_frame(&mut sink, commit(t0 + 10, "b"), &mut replay).unwrap();
+        assert_eq!(n_frames(), 4);
+        handle_frame(&mut sink, commit(t0 + 10, "d"), &mut replay).unwrap();
+        assert_eq!(n_frames(), 5);
+        assert!(replay.is_some());
+        handle_frame(&mut sink, commit(t0 + 20, "e"), &mut replay).unwrap();
+        assert_eq!(n_frames(), 6);
+        assert!(replay.is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
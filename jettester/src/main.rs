@@ -50,6 +50,8 @@ struct WorkerState {
 struct SecondStats {
     second: u64,
     stats: MsgStats,
+    /// Kept separate from `stats`, since it's different for every worker
+    latency: Latency,
 }
 
 // impl SecondStats {
@@ -65,6 +67,22 @@ struct MsgStats {
     hash: u64,
 }
 
+/// How long after its `time_us` we received each event
+#[derive(Debug, Default, Clone, Copy)]
+struct Latency {
+    n_msgs: u64,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl Latency {
+    fn add(&mut self, other: Latency) {
+        self.n_msgs += other.n_msgs;
+        self.total_us += other.total_us;
+        self.max_us = self.max_us.max(other.max_us);
+    }
+}
+
 static N_CONNECTED: AtomicUsize = AtomicUsize::new(0);
 
 pub fn main() {
@@ -168,7 +186,9 @@ pub fn main() {
             let mut new_msgs = 0;
             let mut new_bytes = 0;
             let mut oldest_ts = u64::MAX;
+            let mut latency = Latency::default();
             while let Ok(x) = rx.try_recv() {
+                latency.add(x.latency);
                 new_msgs += x.stats.n_msgs;
                 new_bytes += x.stats.n_bytes;
                 oldest_ts = oldest_ts.min(x.second);
@@ -190,6 +210,13 @@ pub fn main() {
             }
             let mb = new_bytes / 1024 / 1024;
             println!("Total: {} evs, {} MiB = {} Mbps", new_msgs, mb, mb * 8);
+            if latency.n_msgs > 0 {
+                println!(
+                    "Latency: mean {:.1} ms, max {:.1} ms",
+                    latency.total_us as f64 / latency.n_msgs as f64 / 1000.,
+                    latency.max_us as f64 / 1000.,
+                );
+            }
             println!();
 
             std::thread::sleep(Duration::from_secs(1));
@@ -204,6 +231,7 @@ fn worker(
     let mut warming_up = 0;
     let mut last_ts_sec = 0;
     let mut stats = MsgStats::default();
+    let mut latency = Latency::default();
     for frame in iter {
         let frame = frame?;
         ensure!(frame.reserved_bits() == 0, "Non-zero reserved bits");
@@ -224,6 +252,7 @@ fn worker(
             let stats = SecondStats {
                 second: last_ts_sec,
                 stats: std::mem::take(&mut stats),
+                latency: std::mem::take(&mut latency),
             };
             if warming_up < 3 {
                 warming_up += 1;
@@ -234,6 +263,11 @@ fn worker(
         }
         stats.n_bytes += frame.bytes.len();
         stats.n_msgs += 1;
+        let now = Timestamp::now().as_microsecond() as u64;
+        let lag = now.saturating_sub(timestamp);
+        latency.n_msgs += 1;
+        latency.total_us += lag;
+        latency.max_us = latency.max_us.max(lag);
         // for bytes in frame.payload().chunks_exact(8) {
         //     stats.hash ^= u64::from_le_bytes(bytes.try_into().unwrap());
         // }
@@ -1,4 +1,4 @@
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Frame<T = Bytes> {
@@ -29,6 +29,20 @@ pub enum OpCode {
     Custom(u8),
 }
 
+impl OpCode {
+    pub fn to_raw(self) -> u8 {
+        match self {
+            OpCode::Continuation => 0x0,
+            OpCode::Text => 0x1,
+            OpCode::Binary => 0x2,
+            OpCode::Close => 0x8,
+            OpCode::Ping => 0x9,
+            OpCode::Pong => 0xA,
+            OpCode::Custom(x) => x,
+        }
+    }
+}
+
 impl<T: AsRef<[u8]>> Frame<T> {
     pub fn raw_opcode(&self) -> u8 {
         self.bytes.as_ref()[0] & 0b0000_1111
@@ -103,6 +117,29 @@ impl<'a> Frame<&'a [u8]> {
 }
 
 impl Frame {
+    /// Construct a complete (FIN), unmasked frame
+    pub fn new(opcode: OpCode, payload: &[u8]) -> Self {
+        let mut bytes = BytesMut::with_capacity(payload.len() + 10);
+        bytes.put_u8(0b1000_0000 | opcode.to_raw());
+        match payload.len() {
+            n @ ..126 => bytes.put_u8(n as u8),
+            n @ ..=0xFFFF => {
+                bytes.put_u8(126);
+                bytes.put_u16(n as u16);
+            }
+            n => {
+                bytes.put_u8(127);
+                bytes.put_u64(n as u64);
+            }
+        }
+        let header_len = bytes.len();
+        bytes.extend_from_slice(payload);
+        Frame {
+            bytes: bytes.freeze(),
+            header_len,
+        }
+    }
+
     pub fn from_bytes(buffer: &mut impl Buf) -> Result<Self, NeedMoreBytes> {
         let (header_len, payload_len) = parse_length(buffer.chunk())?;
         let total_len = header_len + payload_len;
@@ -141,3 +178,21 @@ fn parse_length(buffer: &[u8]) -> Result<(usize, usize), NeedMoreBytes> {
 
     Ok((header_len, payload_len))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for len in [0, 125, 126, 0xFFFF, 0x10000] {
+            let payload = vec![0x42; len];
+            let frame = Frame::new(OpCode::Binary, &payload);
+            let parsed = Frame::from_slice(&frame.bytes).ok().unwrap();
+            assert_eq!(parsed.opcode(), OpCode::Binary);
+            assert!(parsed.fin());
+            assert!(parsed.mask().is_none());
+            assert_eq!(parsed.payload(), payload);
+        }
+    }
+}
@@ -3,9 +3,11 @@ mod handshake;
 
 pub use crate::frame::{Frame, NeedMoreBytes, OpCode};
 use bytes::BytesMut;
+use jetfsst::Decompressor;
 use std::io::{BufReader, prelude::*};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::time::Duration;
-use std::{fs::File, net::TcpStream, path::Path};
+use std::{fs::File, path::Path};
 use thiserror::Error;
 use url::Url;
 
@@ -43,10 +45,25 @@ pub enum ConnectionError {
 
 pub fn connect_websocket(
     url: &Url,
+) -> Result<impl Iterator<Item = std::io::Result<Frame>> + Send + 'static, ConnectionError> {
+    connect_websocket_with_timeout(url, None)
+}
+
+/// Like `connect_websocket()`, but connecting fails if it takes longer than
+/// `timeout`, and the iterator returns an error if nothing arrives for that
+/// long
+pub fn connect_websocket_with_timeout(
+    url: &Url,
+    timeout: Option<Duration>,
 ) -> Result<impl Iterator<Item = std::io::Result<Frame>> + Send + 'static, ConnectionError> {
     let host = url.host().unwrap().to_string();
     let port = url.port_or_known_default().unwrap();
-    let mut conn = TcpStream::connect((host.as_str(), port))?;
+    let mut conn = match timeout {
+        Some(timeout) => connect_with_timeout(&host, port, timeout)?,
+        None => TcpStream::connect((host.as_str(), port))?,
+    };
+    conn.set_read_timeout(timeout)?;
+    conn.set_write_timeout(timeout)?;
     let tls = match url.scheme() {
         "ws" => false,
         "wss" => true,
@@ -65,7 +82,38 @@ pub fn connect_websocket(
         websocket_handshake_2(&mut conn, &mut buffer)?;
         Box::new(conn)
     };
-    Ok(read_frames(conn, buffer, true))
+    // If we asked for FSST, decode it so the caller sees the usual text frames
+    let fsst = url.query_pairs().any(|(k, _)| k == "fsst");
+    let decompressor = fsst.then(|| Decompressor::new(&jetfsst::PRESET_SYMBOLS));
+    Ok(
+        read_frames(conn, buffer, true).map(move |frame| match &decompressor {
+            Some(x) => decode_fsst(x, frame?),
+            None => frame,
+        }),
+    )
+}
+
+/// `TcpStream::connect_timeout()` only takes a single address, so we try each
+/// of the host's addresses in turn
+fn connect_with_timeout(host: &str, port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(x) => return Ok(x),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| std::io::Error::other(format!("{host}: No addresses"))))
+}
+
+fn decode_fsst(decompressor: &Decompressor, frame: Frame) -> std::io::Result<Frame> {
+    if frame.opcode() != OpCode::Binary {
+        return Ok(frame);
+    }
+    let payload = decompressor
+        .decompress(frame.payload())
+        .map_err(std::io::Error::other)?;
+    Ok(Frame::new(OpCode::Text, &payload))
 }
 
 fn read_frames(
@@ -77,7 +125,7 @@ fn read_frames(
         .take_while(|x| !x.as_ref().is_ok_and(|x| x.opcode() == OpCode::Close))
 }
 
-const MAX_FRAME_SIZE: usize = 64 << 20; // 64 MiB
+pub const MAX_FRAME_SIZE: usize = 64 << 20; // 64 MiB
 
 fn read_frame(
     rdr: &mut impl BufRead,